@@ -0,0 +1,166 @@
+use crate::gdrive::GdriveError;
+use crate::{exe_dir, write_log};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+
+/// Last successfully fetched remote metadata, kept next to the launcher for offline starts.
+const CACHE_FILE_NAME: &str = "remote_cache.json";
+
+pub const VERSION_KEY: &str = "version";
+pub const PATCHES_KEY: &str = "patches";
+pub const MODLIST_KEY: &str = "modlist";
+pub const PACKAGES_KEY: &str = "packages";
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CacheEntry {
+    content: String,
+    fetched_at: DateTime<Utc>,
+}
+
+/// Remote text handed to the frontend. `cached_at` is set when the source could not be
+/// reached and `content` is the copy saved at that time.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RemoteData {
+    pub content: String,
+    pub cached_at: Option<DateTime<Utc>>,
+}
+
+fn cache_path() -> PathBuf {
+    exe_dir().join(CACHE_FILE_NAME)
+}
+
+fn read_all(path: &Path) -> HashMap<String, CacheEntry> {
+    fs::read(path)
+        .ok()
+        .and_then(|content| serde_json::from_slice(&content).ok())
+        .unwrap_or_default()
+}
+
+fn store(path: &Path, key: &str, content: &str) {
+    let mut entries = read_all(path);
+    entries.insert(
+        key.to_string(),
+        CacheEntry {
+            content: content.to_string(),
+            fetched_at: Utc::now(),
+        },
+    );
+    let result = serde_json::to_vec_pretty(&entries)
+        .map_err(|e| e.to_string())
+        .and_then(|json| fs::write(path, json).map_err(|e| e.to_string()));
+    if let Err(e) = result {
+        write_log(&format!("Failed to write {}: {}", CACHE_FILE_NAME, e));
+    }
+}
+
+/// Runs `fetch` and caches its result under `key`. When the source is unreachable, returns
+/// the cached copy instead, if there is one.
+pub async fn with_fallback(
+    key: &str,
+    fetch: impl Future<Output = Result<String, GdriveError>>,
+) -> Result<RemoteData, GdriveError> {
+    with_fallback_in(&cache_path(), key, fetch).await
+}
+
+async fn with_fallback_in(
+    path: &Path,
+    key: &str,
+    fetch: impl Future<Output = Result<String, GdriveError>>,
+) -> Result<RemoteData, GdriveError> {
+    match fetch.await {
+        Ok(content) => {
+            store(path, key, &content);
+            Ok(RemoteData {
+                content,
+                cached_at: None,
+            })
+        }
+        Err(GdriveError::Network(e)) => match read_all(path).remove(key) {
+            Some(entry) => {
+                write_log(&format!(
+                    "Offline ({}), using {} from {}",
+                    e,
+                    key,
+                    entry.fetched_at.to_rfc3339()
+                ));
+                Ok(RemoteData {
+                    content: entry.content,
+                    cached_at: Some(entry.fetched_at),
+                })
+            }
+            None => Err(GdriveError::Network(e)),
+        },
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    fn cache_file(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("cache-{}-{}.json", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn fetch(
+        result: Result<&str, GdriveError>,
+    ) -> impl Future<Output = Result<String, GdriveError>> {
+        let result = result.map(str::to_string);
+        async move { result }
+    }
+
+    #[test]
+    fn stores_what_was_fetched() {
+        let path = cache_file("store");
+        let data = block_on(with_fallback_in(&path, VERSION_KEY, fetch(Ok("6.2")))).unwrap();
+        assert_eq!(data.content, "6.2");
+        assert!(data.cached_at.is_none());
+        assert_eq!(read_all(&path)[VERSION_KEY].content, "6.2");
+
+        block_on(with_fallback_in(&path, PATCHES_KEY, fetch(Ok("[]")))).unwrap();
+        assert_eq!(read_all(&path).len(), 2);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn serves_the_cached_copy_when_offline() {
+        let path = cache_file("offline");
+        block_on(with_fallback_in(&path, VERSION_KEY, fetch(Ok("6.2")))).unwrap();
+        let fetched_at = read_all(&path)[VERSION_KEY].fetched_at;
+
+        let offline = fetch(Err(GdriveError::Network("timed out".into())));
+        let data = block_on(with_fallback_in(&path, VERSION_KEY, offline)).unwrap();
+        assert_eq!(data.content, "6.2");
+        assert_eq!(data.cached_at, Some(fetched_at));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn offline_without_a_copy_fails() {
+        let path = cache_file("empty");
+        let offline = fetch(Err(GdriveError::Network("timed out".into())));
+        let result = block_on(with_fallback_in(&path, VERSION_KEY, offline));
+        assert!(matches!(result, Err(GdriveError::Network(message)) if message == "timed out"));
+    }
+
+    #[test]
+    fn passes_other_errors_through() {
+        let path = cache_file("errors");
+        block_on(with_fallback_in(&path, VERSION_KEY, fetch(Ok("6.2")))).unwrap();
+
+        let missing = fetch(Err(GdriveError::NotFound("version".into())));
+        let result = block_on(with_fallback_in(&path, VERSION_KEY, missing));
+        assert!(matches!(result, Err(GdriveError::NotFound(message)) if message == "version"));
+        assert_eq!(read_all(&path)[VERSION_KEY].content, "6.2");
+        fs::remove_file(&path).unwrap();
+    }
+}
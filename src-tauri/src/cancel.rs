@@ -0,0 +1,108 @@
+use crate::gdrive::GdriveError;
+use crate::write_log;
+use std::sync::Mutex;
+use tauri::{AppHandle, Manager};
+use tokio_util::sync::CancellationToken;
+
+/// Managed state shared by the commands that download or install, so that one
+/// `cancel_operation` stops whatever is running.
+#[derive(Default)]
+pub struct Operations(Mutex<State>);
+
+#[derive(Default)]
+struct State {
+    token: CancellationToken,
+    running: usize,
+    /// The window was closed while something was running; quit once it has rolled back.
+    exit_when_idle: bool,
+}
+
+impl Operations {
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Marks an operation as running until dropped.
+pub struct Operation {
+    app: AppHandle,
+    token: CancellationToken,
+}
+
+impl Operation {
+    /// Cancelled by `cancel_operation`. Passed down to whatever downloads or unpacks for this
+    /// operation; nothing else checks it.
+    pub fn token(&self) -> &CancellationToken {
+        &self.token
+    }
+}
+
+impl Drop for Operation {
+    fn drop(&mut self) {
+        let operations = self.app.state::<Operations>();
+        let mut state = operations.state();
+        state.running -= 1;
+        if state.running == 0 {
+            // The next operation starts with a token that was never cancelled.
+            state.token = CancellationToken::new();
+            if state.exit_when_idle {
+                drop(state);
+                self.app.exit(0);
+            }
+        }
+    }
+}
+
+/// Registers a running operation. A cancel issued before it started does not apply to it.
+pub fn start(app: &AppHandle) -> Operation {
+    let operations = app.state::<Operations>();
+    let mut state = operations.state();
+    state.running += 1;
+    Operation {
+        app: app.clone(),
+        token: state.token.clone(),
+    }
+}
+
+/// A token for work outside any operation, such as checking for updates, which
+/// `cancel_operation` must not stop.
+pub fn uncancellable() -> CancellationToken {
+    CancellationToken::new()
+}
+
+pub fn is_running(app: &AppHandle) -> bool {
+    app.state::<Operations>().state().running > 0
+}
+
+/// Asks every running operation to stop. Returns whether there was any.
+pub fn cancel(app: &AppHandle) -> bool {
+    cancel_running(app, false)
+}
+
+/// Cancels what is running and quits once it has rolled back. Returns `false` when nothing
+/// runs and the window may close right away.
+pub fn cancel_and_exit(app: &AppHandle) -> bool {
+    cancel_running(app, true)
+}
+
+fn cancel_running(app: &AppHandle, exit: bool) -> bool {
+    let operations = app.state::<Operations>();
+    let mut state = operations.state();
+    if state.running == 0 {
+        return false;
+    }
+    write_log("Cancelling the running operation");
+    state.token.cancel();
+    state.exit_when_idle |= exit;
+    true
+}
+
+/// Fails with [`GdriveError::Cancelled`] once the operation `token` belongs to has been
+/// cancelled.
+pub fn check(token: &CancellationToken) -> Result<(), GdriveError> {
+    if token.is_cancelled() {
+        Err(GdriveError::Cancelled("Cancelled by the user".into()))
+    } else {
+        Ok(())
+    }
+}
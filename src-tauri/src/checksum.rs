@@ -0,0 +1,230 @@
+use crate::gdrive::GdriveError;
+use crate::source::RemoteFile;
+use md5::Md5;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// Name of the optional `sha256sum`-style manifest published next to the patch archives.
+pub const MANIFEST_FILE_NAME: &str = "sha256sums";
+
+#[derive(Clone, Debug)]
+pub enum Checksum {
+    Md5(String),
+    Sha256(String),
+}
+
+impl Checksum {
+    fn algorithm(&self) -> &'static str {
+        match self {
+            Checksum::Md5(_) => "MD5",
+            Checksum::Sha256(_) => "SHA-256",
+        }
+    }
+
+    fn expected(&self) -> &str {
+        match self {
+            Checksum::Md5(hash) | Checksum::Sha256(hash) => hash,
+        }
+    }
+}
+
+/// Parses `<hex>  <name>` lines as written by `sha256sum`, keyed by file name.
+pub fn parse_manifest(content: &str) -> HashMap<String, String> {
+    content
+        .lines()
+        .map(|line| line.trim().trim_start_matches('\u{FEFF}'))
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let (hash, name) = line.split_once(char::is_whitespace)?;
+            let name = name.trim_start().trim_start_matches('*');
+            Some((name.to_string(), hash.to_lowercase()))
+        })
+        .collect()
+}
+
+/// Picks what `file` should be checked against: its manifest entry if there is one,
+/// otherwise the MD5 reported by the source.
+pub fn expected_for(file: &RemoteFile, manifest: &HashMap<String, String>) -> Option<Checksum> {
+    manifest
+        .get(&file.name)
+        .map(|hash| Checksum::Sha256(hash.clone()))
+        .or_else(|| {
+            file.md5
+                .as_ref()
+                .map(|hash| Checksum::Md5(hash.to_lowercase()))
+        })
+}
+
+/// Hashes a download chunk by chunk while it streams past, for content that is never
+/// stored as a whole.
+pub struct StreamHasher {
+    expected: Checksum,
+    state: HasherState,
+}
+
+enum HasherState {
+    Md5(Md5),
+    Sha256(Sha256),
+}
+
+impl StreamHasher {
+    pub fn new(expected: Checksum) -> Self {
+        let state = match expected {
+            Checksum::Md5(_) => HasherState::Md5(Md5::new()),
+            Checksum::Sha256(_) => HasherState::Sha256(Sha256::new()),
+        };
+        Self { expected, state }
+    }
+
+    pub fn update(&mut self, chunk: &[u8]) {
+        match &mut self.state {
+            HasherState::Md5(hasher) => hasher.update(chunk),
+            HasherState::Sha256(hasher) => hasher.update(chunk),
+        }
+    }
+
+    pub fn verify(self, name: &str) -> Result<(), GdriveError> {
+        let actual = match self.state {
+            HasherState::Md5(hasher) => to_hex(&hasher.finalize()),
+            HasherState::Sha256(hasher) => to_hex(&hasher.finalize()),
+        };
+        compare(&self.expected, &actual, name)
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn hash_file<D: Digest>(path: &Path) -> Result<String, GdriveError> {
+    let mut file = File::open(path)?;
+    let mut hasher = D::new();
+    let mut buffer = vec![0u8; 1024 * 1024];
+    loop {
+        let bytes_read = file.read(&mut buffer)?;
+        if bytes_read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..bytes_read]);
+    }
+    Ok(to_hex(&hasher.finalize()))
+}
+
+/// Checks a downloaded `file` at `path`: first its size, when the source reported one,
+/// then its hash, when one is known.
+pub async fn verify_download(
+    path: &Path,
+    file: &RemoteFile,
+    expected: Option<Checksum>,
+) -> Result<(), GdriveError> {
+    if let Some(size) = file.size {
+        let actual = std::fs::metadata(path)?.len();
+        if actual != size {
+            return Err(GdriveError::Corrupted(format!(
+                "Size mismatch for {}: expected {} bytes, got {}",
+                path.display(),
+                size,
+                actual
+            )));
+        }
+    }
+
+    match expected {
+        Some(expected) => verify(path.to_path_buf(), expected).await,
+        None => Ok(()),
+    }
+}
+
+/// Hashes `path` off the async runtime and compares it with `expected`.
+pub async fn verify(path: PathBuf, expected: Checksum) -> Result<(), GdriveError> {
+    let name = path.display().to_string();
+    let actual = tokio::task::spawn_blocking({
+        let expected = expected.clone();
+        move || match expected {
+            Checksum::Md5(_) => hash_file::<Md5>(&path),
+            Checksum::Sha256(_) => hash_file::<Sha256>(&path),
+        }
+    })
+    .await
+    .map_err(|e| GdriveError::Io(e.to_string()))??;
+
+    compare(&expected, &actual, &name)
+}
+
+fn compare(expected: &Checksum, actual: &str, name: &str) -> Result<(), GdriveError> {
+    if actual == expected.expected() {
+        Ok(())
+    } else {
+        Err(GdriveError::Corrupted(format!(
+            "{} checksum mismatch for {}: expected {}, got {}",
+            expected.algorithm(),
+            name,
+            expected.expected(),
+            actual
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn remote(name: &str, md5: Option<&str>) -> RemoteFile {
+        RemoteFile {
+            id: name.to_string(),
+            name: name.to_string(),
+            mime_type: "application/zip".to_string(),
+            size: None,
+            modified_time: None,
+            md5: md5.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn parses_sha256sum_output() {
+        let manifest = parse_manifest(
+            "\u{FEFF}# published with the 6.2 release\n\
+             ABCDEF  RFAD_PATCH-6.2.zip\r\n\
+             \n\
+             123456 *RFAD_PATCH 6.1.7z\n\
+             not-a-line\n",
+        );
+        assert_eq!(manifest.len(), 2);
+        assert_eq!(manifest["RFAD_PATCH-6.2.zip"], "abcdef");
+        assert_eq!(manifest["RFAD_PATCH 6.1.7z"], "123456");
+    }
+
+    #[test]
+    fn manifest_entry_wins_over_md5() {
+        let manifest = parse_manifest("aa  update.zip\n");
+        assert!(matches!(
+            expected_for(&remote("update.zip", Some("BB")), &manifest),
+            Some(Checksum::Sha256(hash)) if hash == "aa"
+        ));
+        assert!(matches!(
+            expected_for(&remote("other.zip", Some("BB")), &manifest),
+            Some(Checksum::Md5(hash)) if hash == "bb"
+        ));
+        assert!(expected_for(&remote("other.zip", None), &manifest).is_none());
+    }
+
+    #[test]
+    fn stream_hasher_checks_the_whole_stream() {
+        // SHA-256 of "abc".
+        let expected = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        let mut hasher = StreamHasher::new(Checksum::Sha256(expected.to_string()));
+        hasher.update(b"a");
+        hasher.update(b"bc");
+        assert!(hasher.verify("abc").is_ok());
+
+        let mut hasher = StreamHasher::new(Checksum::Sha256(expected.to_string()));
+        hasher.update(b"ab");
+        assert!(matches!(
+            hasher.verify("ab"),
+            Err(GdriveError::Corrupted(_))
+        ));
+    }
+}
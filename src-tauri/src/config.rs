@@ -0,0 +1,214 @@
+use crate::{exe_dir, write_log};
+use google_drive3::yup_oauth2;
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const CONFIG_FILE_NAME: &str = "launcher.toml";
+const MAX_CONNECTIONS: usize = 16;
+/// Written on the first start without [`CONFIG_FILE_NAME`], e.g. right after a launcher
+/// that had the source built in updated itself to this one.
+const DEFAULT_CONFIG: &str = include_str!("../launcher.default.toml");
+/// Where [`DEFAULT_CONFIG`] expects the service-account key.
+const DEFAULT_CREDENTIALS_FILE_NAME: &str = "credentials.json";
+/// Key written next to the default config, taken from the release build's environment so
+/// that it never lands in the repository.
+const BUNDLED_CREDENTIALS: Option<&str> = option_env!("RFAD_CREDENTIALS_JSON");
+
+/// `launcher.toml`, read once at startup from the launcher's folder.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LauncherConfig {
+    pub source: SourceConfig,
+    #[serde(default)]
+    pub download: DownloadConfig,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DownloadConfig {
+    /// Parallel connections used for large archives; 1 downloads over a single stream.
+    pub connections: usize,
+    /// Unpack the patch archive while it downloads instead of saving it first.
+    pub stream_unpack: bool,
+}
+
+impl Default for DownloadConfig {
+    fn default() -> Self {
+        Self {
+            connections: 1,
+            stream_unpack: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum SourceConfig {
+    GoogleDrive {
+        folder_id: String,
+        /// Service-account key JSON, relative to the launcher's folder unless absolute.
+        credentials: PathBuf,
+        /// Overrides the Drive API endpoint, e.g. for a proxy.
+        api_url: Option<String>,
+    },
+    Http {
+        base_url: String,
+    },
+    Local {
+        path: PathBuf,
+    },
+}
+
+/// Managed state holding either the parsed config or everything wrong with it.
+pub struct ConfigState(pub Result<LauncherConfig, Vec<String>>);
+
+impl ConfigState {
+    pub fn get(&self) -> Result<&LauncherConfig, String> {
+        self.0.as_ref().map_err(|errors| errors.join("; "))
+    }
+}
+
+/// Resolves `path` against the launcher's folder when it is relative.
+pub fn resolve_path(path: &Path) -> PathBuf {
+    if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        exe_dir().join(path)
+    }
+}
+
+pub fn load() -> ConfigState {
+    let path = exe_dir().join(CONFIG_FILE_NAME);
+    if !path.exists() {
+        write_defaults(&path);
+    }
+    write_log(&format!("Loading launcher config from {}", path.display()));
+
+    let result = fs::read_to_string(&path)
+        .map_err(|e| vec![format!("Failed to read {}: {}", CONFIG_FILE_NAME, e)])
+        .and_then(|content| {
+            toml::from_str::<LauncherConfig>(&content)
+                .map_err(|e| vec![format!("Invalid {}: {}", CONFIG_FILE_NAME, e)])
+        })
+        .and_then(|config| {
+            let errors = validate(&config);
+            if errors.is_empty() {
+                Ok(config)
+            } else {
+                Err(errors)
+            }
+        });
+
+    if let Err(errors) = &result {
+        for error in errors {
+            write_log(error);
+        }
+    }
+    ConfigState(result)
+}
+
+/// Writes [`DEFAULT_CONFIG`] to `path`, along with the bundled key unless one is there
+/// already. Failures only get logged: loading then reports the missing file.
+fn write_defaults(path: &Path) {
+    write_log(&format!("No {}, writing the default one", CONFIG_FILE_NAME));
+    if let Err(e) = fs::write(path, DEFAULT_CONFIG) {
+        write_log(&format!("Failed to write {}: {}", path.display(), e));
+        return;
+    }
+
+    let credentials = exe_dir().join(DEFAULT_CREDENTIALS_FILE_NAME);
+    match BUNDLED_CREDENTIALS {
+        Some(key) if !credentials.exists() => {
+            if let Err(e) = fs::write(&credentials, key) {
+                write_log(&format!("Failed to write {}: {}", credentials.display(), e));
+            }
+        }
+        Some(_) => {}
+        None => write_log("This build has no bundled credentials"),
+    }
+}
+
+fn validate(config: &LauncherConfig) -> Vec<String> {
+    let mut errors = Vec::new();
+
+    match &config.source {
+        SourceConfig::GoogleDrive {
+            folder_id,
+            credentials,
+            api_url,
+        } => {
+            if folder_id.trim().is_empty() {
+                errors.push("source.folder_id must not be empty".to_string());
+            }
+            let credentials = resolve_path(credentials);
+            match fs::read(&credentials) {
+                Ok(key) => {
+                    if let Err(e) = yup_oauth2::parse_service_account_key(key) {
+                        errors.push(format!("{}: {}", credentials.display(), e));
+                    }
+                }
+                Err(e) => errors.push(format!(
+                    "Failed to read credentials {}: {}",
+                    credentials.display(),
+                    e
+                )),
+            }
+            if let Some(url) = api_url {
+                if !is_http_url(url) {
+                    errors.push(format!("source.api_url is not an http(s) URL: {}", url));
+                }
+            }
+        }
+        SourceConfig::Http { base_url } => {
+            if !is_http_url(base_url) {
+                errors.push(format!(
+                    "source.base_url is not an http(s) URL: {}",
+                    base_url
+                ));
+            }
+        }
+        SourceConfig::Local { path } => {
+            let path = resolve_path(path);
+            if !path.is_dir() {
+                errors.push(format!("source.path is not a folder: {}", path.display()));
+            }
+        }
+    }
+
+    if !(1..=MAX_CONNECTIONS).contains(&config.download.connections) {
+        errors.push(format!(
+            "download.connections must be between 1 and {}",
+            MAX_CONNECTIONS
+        ));
+    }
+
+    errors
+}
+
+fn is_http_url(url: &str) -> bool {
+    url.starts_with("https://") || url.starts_with("http://")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_config_parses() {
+        let config: LauncherConfig = toml::from_str(DEFAULT_CONFIG).unwrap();
+        match config.source {
+            SourceConfig::GoogleDrive { credentials, .. } => {
+                assert_eq!(credentials, Path::new(DEFAULT_CREDENTIALS_FILE_NAME))
+            }
+            other => panic!("unexpected default source {:?}", other),
+        }
+    }
+
+    #[test]
+    fn bundled_credentials_are_a_service_account_key() {
+        if let Some(key) = BUNDLED_CREDENTIALS {
+            yup_oauth2::parse_service_account_key(key).unwrap();
+        }
+    }
+}
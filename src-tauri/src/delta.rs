@@ -0,0 +1,480 @@
+use crate::checksum;
+use crate::gdrive::GdriveError;
+use crate::versions::PatchVersion;
+use crate::{write_log, LOCAL_VERSION_FILE_NAME};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// Published next to the patch archive for releases that can be installed file by file.
+pub const MANIFEST_FILE_NAME: &str = "patch-manifest.json";
+/// Folder next to the manifest holding the unpacked patch tree.
+pub const FILES_DIR_NAME: &str = "files";
+/// Copy of the manifest of the last file-by-file update, kept in `MO2/` so that the patch
+/// can be verified after a newer manifest is published.
+const INSTALLED_MANIFEST_FILE_NAME: &str = "installed-manifest.json";
+/// Folder in `MO2/` the files of an update are downloaded into before they are moved into
+/// the patch folder. Kept when the update fails, so that the next attempt picks up there.
+const DOWNLOAD_DIR_NAME: &str = "delta_download";
+
+/// Every file of a release, e.g.
+/// `{ "version": "6.2.1", "files": [{ "path": "RFAD_PATCH.esp", "size": 1024, "sha256": "..." }] }`.
+#[derive(Deserialize, Serialize)]
+pub struct PatchManifest {
+    pub version: PatchVersion,
+    pub files: Vec<ManifestEntry>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct ManifestEntry {
+    /// `/`-separated, relative to the patch folder.
+    pub path: String,
+    pub size: u64,
+    pub sha256: String,
+    /// Modification time of the installed file in nanoseconds since the Unix epoch. Only in
+    /// the copy saved by [`save_installed`], never published.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modified: Option<u64>,
+}
+
+/// What has to change in the local patch folder to match a manifest.
+pub struct Plan {
+    pub download: Vec<ManifestEntry>,
+    pub remove: Vec<String>,
+}
+
+impl Plan {
+    pub fn download_size(&self) -> u64 {
+        self.download.iter().map(|entry| entry.size).sum()
+    }
+
+    /// Every path the plan writes or deletes.
+    pub fn touched(&self) -> Vec<String> {
+        self.download
+            .iter()
+            .map(|entry| entry.path.clone())
+            .chain(self.remove.iter().cloned())
+            .collect()
+    }
+}
+
+/// How the patch folder differs from a manifest, as `/`-separated paths.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IntegrityReport {
+    pub version: PatchVersion,
+    pub missing: Vec<String>,
+    /// Files whose size or SHA-256 differ from the manifest.
+    pub modified: Vec<String>,
+    /// Files the manifest does not list.
+    pub extra: Vec<String>,
+}
+
+impl IntegrityReport {
+    /// Manifest entries that have to be downloaded again.
+    pub fn broken(&self, manifest: &PatchManifest) -> Vec<ManifestEntry> {
+        let broken: HashSet<&str> = self
+            .missing
+            .iter()
+            .chain(&self.modified)
+            .map(String::as_str)
+            .collect();
+        manifest
+            .files
+            .iter()
+            .filter(|entry| broken.contains(entry.path.as_str()))
+            .cloned()
+            .collect()
+    }
+}
+
+pub fn parse_manifest(content: &str) -> Result<PatchManifest, GdriveError> {
+    let manifest: PatchManifest = serde_json::from_str(content.trim_start_matches('\u{FEFF}'))
+        .map_err(|e| GdriveError::Decode(format!("Invalid {}: {}", MANIFEST_FILE_NAME, e)))?;
+
+    if let Some(entry) = manifest
+        .files
+        .iter()
+        .find(|entry| !is_safe_path(&entry.path))
+    {
+        return Err(GdriveError::Corrupted(format!(
+            "Unsafe path in {}: {}",
+            MANIFEST_FILE_NAME, entry.path
+        )));
+    }
+    Ok(manifest)
+}
+
+fn is_safe_path(path: &str) -> bool {
+    !path.is_empty()
+        && Path::new(path)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+}
+
+/// Saves `manifest` into `base_dir` once `patch_dir` matches it, along with the modification
+/// time of every file, so that the next [`plan`] only hashes the files changed since.
+pub fn save_installed(
+    base_dir: &Path,
+    manifest: &PatchManifest,
+    patch_dir: &Path,
+) -> Result<(), GdriveError> {
+    let files = manifest
+        .files
+        .iter()
+        .map(|entry| ManifestEntry {
+            modified: fs::metadata(patch_dir.join(&entry.path))
+                .ok()
+                .and_then(|metadata| modified_nanos(&metadata)),
+            ..entry.clone()
+        })
+        .collect();
+    let installed = PatchManifest {
+        version: manifest.version.clone(),
+        files,
+    };
+    let content =
+        serde_json::to_vec_pretty(&installed).map_err(|e| GdriveError::Io(e.to_string()))?;
+    Ok(fs::write(
+        base_dir.join(INSTALLED_MANIFEST_FILE_NAME),
+        content,
+    )?)
+}
+
+/// The manifest saved by [`save_installed`], if any.
+pub fn read_installed(base_dir: &Path) -> Option<PatchManifest> {
+    let content = fs::read_to_string(base_dir.join(INSTALLED_MANIFEST_FILE_NAME)).ok()?;
+    parse_manifest(&content).ok()
+}
+
+pub fn download_dir(base_dir: &Path) -> PathBuf {
+    base_dir.join(DOWNLOAD_DIR_NAME)
+}
+
+/// Moves `entries` from the download folder into `patch_dir`.
+pub fn move_downloaded(
+    base_dir: &Path,
+    patch_dir: &Path,
+    entries: &[ManifestEntry],
+) -> Result<(), GdriveError> {
+    let download_dir = download_dir(base_dir);
+    for entry in entries {
+        let target = patch_dir.join(&entry.path);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(download_dir.join(&entry.path), &target)?;
+    }
+    Ok(())
+}
+
+/// Deletes `paths` of `patch_dir`. Files that are gone already, e.g. moved into the backup
+/// by [`crate::install::Transaction::begin_files`], are fine.
+pub fn remove_files(patch_dir: &Path, paths: &[String]) -> Result<(), GdriveError> {
+    for path in paths {
+        match fs::remove_file(patch_dir.join(path)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Drops the download folder once its files are installed.
+pub fn clear_downloads(base_dir: &Path) {
+    let dir = download_dir(base_dir);
+    if dir.exists() {
+        if let Err(e) = fs::remove_dir_all(&dir) {
+            write_log(&format!("Failed to remove {}: {}", dir.display(), e));
+        }
+    }
+}
+
+/// What has to be downloaded and deleted for `patch_dir` to match `manifest`. `installed` is
+/// the manifest saved by the last update: files that kept the size and modification time it
+/// recorded are not hashed again, and only files it lists and `manifest` does not are deleted,
+/// so that files the player added stay. Without it, as after a full archive install, every
+/// file `manifest` does not list is deleted. `version.txt` is always kept, the update writes
+/// it itself.
+pub async fn plan(
+    manifest: &PatchManifest,
+    patch_dir: PathBuf,
+    installed: Option<PatchManifest>,
+) -> Result<Plan, GdriveError> {
+    let files = manifest.files.clone();
+    tokio::task::spawn_blocking(move || plan_files(&files, &patch_dir, installed))
+        .await
+        .map_err(|e| GdriveError::Io(e.to_string()))?
+}
+
+fn plan_files(
+    files: &[ManifestEntry],
+    patch_dir: &Path,
+    installed: Option<PatchManifest>,
+) -> Result<Plan, GdriveError> {
+    let candidates = match &installed {
+        Some(installed) => installed
+            .files
+            .iter()
+            .map(|entry| entry.path.clone())
+            .collect(),
+        None => local_files(patch_dir)?,
+    };
+    let recorded: HashMap<String, ManifestEntry> = installed
+        .map(|installed| installed.files)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|entry| (entry.path.clone(), entry))
+        .collect();
+    let published: HashSet<&str> = files.iter().map(|entry| entry.path.as_str()).collect();
+    let mut remove: Vec<String> = candidates
+        .into_iter()
+        .filter(|path| {
+            !published.contains(path.as_str())
+                && path != LOCAL_VERSION_FILE_NAME
+                && patch_dir.join(path).is_file()
+        })
+        .collect();
+    remove.sort();
+
+    let mut download = Vec::new();
+    for entry in files {
+        let path = patch_dir.join(&entry.path);
+        let intact = match fs::metadata(&path) {
+            Ok(metadata) if metadata.is_file() => {
+                is_intact(entry, &path, &metadata, recorded.get(&entry.path))?
+            }
+            _ => false,
+        };
+        if !intact {
+            download.push(entry.clone());
+        }
+    }
+    Ok(Plan { download, remove })
+}
+
+/// Whether the file at `path` has the size and SHA-256 of `entry`. Hashing is skipped when
+/// `recorded` saw the same content with the same size and modification time.
+fn is_intact(
+    entry: &ManifestEntry,
+    path: &Path,
+    metadata: &fs::Metadata,
+    recorded: Option<&ManifestEntry>,
+) -> Result<bool, GdriveError> {
+    if metadata.len() != entry.size {
+        return Ok(false);
+    }
+    let untouched = recorded.is_some_and(|recorded| {
+        recorded.size == entry.size
+            && recorded.sha256.eq_ignore_ascii_case(&entry.sha256)
+            && recorded.modified.is_some()
+            && recorded.modified == modified_nanos(metadata)
+    });
+    Ok(untouched || checksum::hash_file::<Sha256>(path)? == entry.sha256.to_lowercase())
+}
+
+fn modified_nanos(metadata: &fs::Metadata) -> Option<u64> {
+    let since_epoch = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    u64::try_from(since_epoch.as_nanos()).ok()
+}
+
+/// Compares `manifest` with `patch_dir`, by size first and by SHA-256 when sizes match.
+/// Hashing runs off the async runtime.
+pub async fn verify(
+    manifest: &PatchManifest,
+    patch_dir: PathBuf,
+) -> Result<IntegrityReport, GdriveError> {
+    let files = manifest.files.clone();
+    let version = manifest.version.clone();
+    tokio::task::spawn_blocking(move || {
+        let expected: HashSet<&str> = files.iter().map(|entry| entry.path.as_str()).collect();
+        let extra = local_files(&patch_dir)?
+            .into_iter()
+            .filter(|path| !expected.contains(path.as_str()))
+            .collect();
+
+        let mut missing = Vec::new();
+        let mut modified = Vec::new();
+        for entry in &files {
+            let path = patch_dir.join(&entry.path);
+            match fs::metadata(&path) {
+                Ok(metadata) if metadata.is_file() => {
+                    if !is_intact(entry, &path, &metadata, None)? {
+                        modified.push(entry.path.clone());
+                    }
+                }
+                _ => missing.push(entry.path.clone()),
+            }
+        }
+
+        Ok(IntegrityReport {
+            version,
+            missing,
+            modified,
+            extra,
+        })
+    })
+    .await
+    .map_err(|e| GdriveError::Io(e.to_string()))?
+}
+
+/// Files under `root` as `/`-separated relative paths.
+pub fn local_files(root: &Path) -> Result<Vec<String>, GdriveError> {
+    let mut files = Vec::new();
+    let mut folders = vec![root.to_path_buf()];
+
+    while let Some(folder) = folders.pop() {
+        let Ok(entries) = fs::read_dir(&folder) else {
+            continue;
+        };
+        for entry in entries {
+            let path = entry?.path();
+            if path.is_dir() {
+                folders.push(path);
+            } else if let Ok(relative) = path.strip_prefix(root) {
+                files.push(relative.to_string_lossy().replace('\\', "/"));
+            }
+        }
+    }
+
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("delta-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn entry(dir: &Path, path: &str, content: &str) -> ManifestEntry {
+        fs::write(dir.join(path), content).unwrap();
+        ManifestEntry {
+            path: path.to_string(),
+            size: content.len() as u64,
+            sha256: checksum::hash_file::<Sha256>(&dir.join(path)).unwrap(),
+            modified: None,
+        }
+    }
+
+    fn installed(dir: &Path, files: &[ManifestEntry]) -> PatchManifest {
+        PatchManifest {
+            version: PatchVersion::parse("1.0").unwrap(),
+            files: files
+                .iter()
+                .map(|entry| ManifestEntry {
+                    modified: modified_nanos(&fs::metadata(dir.join(&entry.path)).unwrap()),
+                    ..entry.clone()
+                })
+                .collect(),
+        }
+    }
+
+    fn paths(entries: &[ManifestEntry]) -> Vec<&str> {
+        entries.iter().map(|entry| entry.path.as_str()).collect()
+    }
+
+    #[test]
+    fn downloads_missing_and_changed_files() {
+        let dir = patch_dir("changed");
+        let same = entry(&dir, "same.esp", "same");
+        let changed = ManifestEntry {
+            sha256: "0".repeat(64),
+            ..entry(&dir, "changed.esp", "abcd")
+        };
+        let missing = ManifestEntry {
+            path: "missing.esp".to_string(),
+            ..same.clone()
+        };
+
+        let plan = plan_files(&[same, changed, missing], &dir, None).unwrap();
+        assert_eq!(paths(&plan.download), ["changed.esp", "missing.esp"]);
+        assert!(plan.remove.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn trusts_files_the_last_update_left_untouched() {
+        let dir = patch_dir("untouched");
+        // The wrong hash shows the file is not read again.
+        let file = ManifestEntry {
+            sha256: "0".repeat(64),
+            ..entry(&dir, "patch.esp", "content")
+        };
+        let recorded = installed(&dir, std::slice::from_ref(&file));
+
+        let plan = plan_files(std::slice::from_ref(&file), &dir, Some(recorded)).unwrap();
+        assert!(plan.download.is_empty());
+
+        let plan = plan_files(&[file], &dir, None).unwrap();
+        assert_eq!(paths(&plan.download), ["patch.esp"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rehashes_files_with_another_size() {
+        let dir = patch_dir("resized");
+        let file = entry(&dir, "patch.esp", "content");
+        let recorded = installed(&dir, std::slice::from_ref(&file));
+        let grown = ManifestEntry {
+            size: file.size + 1,
+            ..file
+        };
+
+        let plan = plan_files(&[grown], &dir, Some(recorded)).unwrap();
+        assert_eq!(paths(&plan.download), ["patch.esp"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn removes_only_files_of_the_last_update() {
+        let dir = patch_dir("removed");
+        let kept = entry(&dir, "kept.esp", "kept");
+        let dropped = entry(&dir, "dropped.esp", "dropped");
+        entry(&dir, "player.ini", "player");
+        let gone = ManifestEntry {
+            path: "gone.esp".to_string(),
+            ..kept.clone()
+        };
+        let recorded = installed(&dir, &[kept.clone(), dropped]);
+        let recorded = PatchManifest {
+            files: [recorded.files, vec![gone]].concat(),
+            ..recorded
+        };
+
+        let plan = plan_files(std::slice::from_ref(&kept), &dir, Some(recorded)).unwrap();
+        assert_eq!(plan.remove, ["dropped.esp"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn without_a_record_removes_unlisted_files() {
+        let dir = patch_dir("unrecorded");
+        let kept = entry(&dir, "kept.esp", "kept");
+        entry(&dir, "dropped.esp", "dropped");
+        entry(&dir, LOCAL_VERSION_FILE_NAME, "6.2");
+
+        let plan = plan_files(&[kept], &dir, None).unwrap();
+        assert_eq!(plan.remove, ["dropped.esp"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn removing_a_missing_file_is_fine() {
+        let dir = patch_dir("missing");
+        entry(&dir, "dropped.esp", "dropped");
+
+        let paths = ["dropped.esp".to_string(), "gone.esp".to_string()];
+        remove_files(&dir, &paths).unwrap();
+        assert!(!dir.join("dropped.esp").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
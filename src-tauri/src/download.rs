@@ -0,0 +1,716 @@
+use crate::events::DownloadProgress;
+use crate::gdrive::GdriveError;
+use futures::TryStreamExt;
+use reqwest::header::{CONTENT_RANGE, ETAG, IF_RANGE, LAST_MODIFIED, RANGE};
+use reqwest::{RequestBuilder, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::io::{self, SeekFrom};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tauri::{AppHandle, Emitter, Manager};
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio_util::sync::CancellationToken;
+
+/// Files smaller than this are always fetched over a single connection.
+const MIN_CHUNKED_SIZE: u64 = 32 * 1024 * 1024;
+/// Attempts per byte range before a chunked download gives up.
+const CHUNK_ATTEMPTS: u32 = 3;
+/// How often the reported speed is recomputed.
+const SPEED_WINDOW: Duration = Duration::from_secs(1);
+
+/// Managed state with the download bandwidth limit in bytes per second, 0 for none. Read on
+/// every chunk, so a new value applies to downloads already running.
+#[derive(Default)]
+pub struct RateLimit(AtomicU64);
+
+impl RateLimit {
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn set(&self, bytes_per_sec: u64) {
+        self.0.store(bytes_per_sec, Ordering::Relaxed);
+    }
+}
+
+/// Sidecar written next to a `.part` file, used to decide whether the next attempt may
+/// continue where this one stopped.
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PartMeta {
+    id: String,
+    total_size: u64,
+    /// `ETag` or `Last-Modified` of the first response, sent back as `If-Range`.
+    validator: Option<String>,
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+fn part_path(output_path: &Path) -> PathBuf {
+    with_suffix(output_path, ".part")
+}
+
+fn meta_path(output_path: &Path) -> PathBuf {
+    with_suffix(output_path, ".part.json")
+}
+
+async fn read_meta(path: &Path) -> Option<PartMeta> {
+    let content = fs::read(path).await.ok()?;
+    serde_json::from_slice(&content).ok()
+}
+
+async fn write_meta(path: &Path, meta: &PartMeta) -> Result<(), GdriveError> {
+    let content = serde_json::to_vec(meta).map_err(|e| GdriveError::Io(e.to_string()))?;
+    Ok(fs::write(path, content).await?)
+}
+
+/// Moves a finished `.part` file into place and drops its sidecar.
+async fn finish(output_path: &Path) -> Result<(), GdriveError> {
+    fs::rename(part_path(output_path), output_path).await?;
+    let _ = fs::remove_file(meta_path(output_path)).await;
+    Ok(())
+}
+
+async fn discard(output_path: &Path) {
+    let _ = fs::remove_file(part_path(output_path)).await;
+    let _ = fs::remove_file(meta_path(output_path)).await;
+}
+
+/// Total length from a `Content-Range: bytes 100-999/1000` header.
+fn content_range_total(response: &reqwest::Response) -> Option<u64> {
+    response
+        .headers()
+        .get(CONTENT_RANGE)?
+        .to_str()
+        .ok()?
+        .rsplit('/')
+        .next()?
+        .parse()
+        .ok()
+}
+
+/// Read errors of a response body: the connection dropped, not the disk failed.
+pub fn network_error(e: io::Error) -> GdriveError {
+    GdriveError::Network(e.to_string())
+}
+
+fn validator(response: &reqwest::Response) -> Option<String> {
+    let headers = response.headers();
+    headers
+        .get(ETAG)
+        .or_else(|| headers.get(LAST_MODIFIED))
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string())
+}
+
+/// What a response means for the `.part` file a resumable download left behind.
+#[derive(Debug, PartialEq)]
+enum Answer {
+    /// The `.part` file already holds every byte.
+    Complete,
+    /// The `.part` file is of no use: drop it and ask for the whole file.
+    Restart,
+    Failed,
+    /// Write the body into the `.part` file from `offset` on.
+    Write {
+        offset: u64,
+        total_size: u64,
+    },
+}
+
+/// Reads the status of a request sent with `offset` bytes already in the `.part` file of a
+/// `previous_size` byte download. A server that finds the `If-Range` validator stale sends
+/// the whole file back with 200, which then overwrites the `.part` file from the start.
+fn answer(
+    offset: u64,
+    previous_size: Option<u64>,
+    status: StatusCode,
+    range_total: Option<u64>,
+    content_length: Option<u64>,
+) -> Answer {
+    if offset > 0 && status == StatusCode::RANGE_NOT_SATISFIABLE {
+        return if previous_size == Some(offset) {
+            Answer::Complete
+        } else {
+            Answer::Restart
+        };
+    }
+    if !status.is_success() {
+        return Answer::Failed;
+    }
+    if offset > 0 && status == StatusCode::PARTIAL_CONTENT {
+        let total_size = range_total.unwrap_or(0);
+        if previous_size.is_some_and(|size| size != total_size) {
+            // The file changed on the server since the last attempt.
+            return Answer::Restart;
+        }
+        return Answer::Write { offset, total_size };
+    }
+    Answer::Write {
+        offset: 0,
+        total_size: content_length.unwrap_or(0),
+    }
+}
+
+/// Downloads `id` into `output_path`. Files of a known `size` above [`MIN_CHUNKED_SIZE`] are
+/// split into byte ranges fetched over `connections` parallel requests; everything else, and
+/// servers that ignore Range, go through [`fetch_resumable`]. Stops once `cancel` is
+/// cancelled.
+pub async fn fetch(
+    request: impl Fn() -> RequestBuilder,
+    id: &str,
+    size: Option<u64>,
+    connections: usize,
+    output_path: &Path,
+    app: &AppHandle,
+    cancel: &CancellationToken,
+) -> Result<(), GdriveError> {
+    if let Some(size) = size.filter(|&size| connections > 1 && size >= MIN_CHUNKED_SIZE) {
+        if fetch_chunked(&request, size, connections, output_path, app, cancel).await? {
+            return Ok(());
+        }
+        crate::write_log("Server does not serve byte ranges, downloading over one connection");
+    }
+    fetch_resumable(request, id, output_path, app, cancel).await
+}
+
+/// Downloads into `<output_path>.part`, resuming an earlier attempt for the same `id` with a
+/// Range request, and renames the file into place once every byte has arrived. `request` must
+/// build a fresh GET for the file each time it is called.
+async fn fetch_resumable(
+    request: impl Fn() -> RequestBuilder,
+    id: &str,
+    output_path: &Path,
+    app: &AppHandle,
+    cancel: &CancellationToken,
+) -> Result<(), GdriveError> {
+    let part = part_path(output_path);
+    let meta = meta_path(output_path);
+
+    let previous = read_meta(&meta).await.filter(|m| m.id == id);
+    let mut offset = match (&previous, fs::metadata(&part).await) {
+        (Some(previous), Ok(existing)) if existing.len() <= previous.total_size => existing.len(),
+        _ => 0,
+    };
+
+    loop {
+        let mut builder = request();
+        if let Some(previous) = previous.as_ref().filter(|_| offset > 0) {
+            builder = builder.header(RANGE, format!("bytes={}-", offset));
+            if let Some(validator) = &previous.validator {
+                builder = builder.header(IF_RANGE, validator);
+            }
+        }
+
+        let response = builder.send().await?;
+        let status = response.status();
+
+        let total_size = match answer(
+            offset,
+            previous.as_ref().map(|p| p.total_size),
+            status,
+            content_range_total(&response),
+            response.content_length(),
+        ) {
+            Answer::Complete => return finish(output_path).await,
+            Answer::Restart => {
+                discard(output_path).await;
+                offset = 0;
+                continue;
+            }
+            Answer::Failed => {
+                let body = response.text().await.unwrap_or_default();
+                return Err(GdriveError::from_status(
+                    status.as_u16(),
+                    format!("Server returned error: {}: {}", status, body),
+                ));
+            }
+            Answer::Write {
+                offset: from,
+                total_size,
+            } => {
+                offset = from;
+                total_size
+            }
+        };
+        let resumed = offset > 0;
+
+        write_meta(
+            &meta,
+            &PartMeta {
+                id: id.to_string(),
+                total_size,
+                validator: validator(&response),
+            },
+        )
+        .await?;
+
+        let mut file = if resumed {
+            OpenOptions::new().append(true).open(&part).await
+        } else {
+            File::create(&part).await
+        }?;
+
+        let stream = response.bytes_stream().map_err(io::Error::other);
+        let reader = tokio_util::io::StreamReader::new(stream);
+        let progress = Progress::new(output_path, offset, total_size, app, cancel);
+        let downloaded = copy_with_progress(reader, network_error, &mut file, &progress).await?;
+
+        if total_size > 0 && downloaded != total_size {
+            return Err(GdriveError::Network(format!(
+                "Download interrupted at {} of {} bytes",
+                downloaded, total_size
+            )));
+        }
+        return finish(output_path).await;
+    }
+}
+
+/// Fetches `total_size` bytes as `connections` ranges written in place into a preallocated
+/// `.part` file. Returns `false`, leaving nothing behind, when the server answers a range
+/// request with the whole file.
+async fn fetch_chunked(
+    request: &impl Fn() -> RequestBuilder,
+    total_size: u64,
+    connections: usize,
+    output_path: &Path,
+    app: &AppHandle,
+    cancel: &CancellationToken,
+) -> Result<bool, GdriveError> {
+    // Chunks are not tracked across runs, so a leftover single-stream `.part` is useless here.
+    discard(output_path).await;
+    let part = part_path(output_path);
+    File::create(&part).await?.set_len(total_size).await?;
+
+    let chunk_size = total_size.div_ceil(connections as u64);
+    let progress = Progress::new(output_path, 0, total_size, app, cancel);
+    let chunks = (0..connections as u64)
+        .map(|i| i * chunk_size..((i + 1) * chunk_size).min(total_size))
+        .filter(|range| !range.is_empty())
+        .map(|range| fetch_chunk(request, &part, range, total_size, &progress));
+
+    match futures::future::try_join_all(chunks).await {
+        Ok(served) if served.iter().all(|&ranged| ranged) => {
+            finish(output_path).await?;
+            Ok(true)
+        }
+        Ok(_) => {
+            discard(output_path).await;
+            Ok(false)
+        }
+        Err(e) => {
+            discard(output_path).await;
+            Err(e)
+        }
+    }
+}
+
+/// Fills `range` of `part`, retrying dropped connections from the last byte written.
+async fn fetch_chunk(
+    request: &impl Fn() -> RequestBuilder,
+    part: &Path,
+    range: Range<u64>,
+    total_size: u64,
+    progress: &Progress<'_>,
+) -> Result<bool, GdriveError> {
+    let mut file = OpenOptions::new().write(true).open(part).await?;
+    let mut position = range.start;
+    let mut attempt = 1;
+
+    while position < range.end {
+        let result = fetch_range(
+            request,
+            &mut file,
+            &mut position,
+            range.end,
+            total_size,
+            progress,
+        )
+        .await;
+        match result {
+            Ok(true) => {}
+            Ok(false) => return Ok(false),
+            Err(GdriveError::Network(e)) if attempt < CHUNK_ATTEMPTS => {
+                crate::write_log(&format!(
+                    "Range {}-{} failed at {}, retrying: {}",
+                    range.start, range.end, position, e
+                ));
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    file.flush().await?;
+    Ok(true)
+}
+
+/// One request for `position..end`, advancing `position` as bytes are written.
+async fn fetch_range(
+    request: &impl Fn() -> RequestBuilder,
+    file: &mut File,
+    position: &mut u64,
+    end: u64,
+    total_size: u64,
+    progress: &Progress<'_>,
+) -> Result<bool, GdriveError> {
+    let response = request()
+        .header(RANGE, format!("bytes={}-{}", position, end - 1))
+        .send()
+        .await?;
+    let status = response.status();
+
+    if status == StatusCode::OK {
+        return Ok(false);
+    }
+    if status != StatusCode::PARTIAL_CONTENT {
+        let body = response.text().await.unwrap_or_default();
+        return Err(GdriveError::from_status(
+            status.as_u16(),
+            format!("Server returned error: {}: {}", status, body),
+        ));
+    }
+    if content_range_total(&response) != Some(total_size) {
+        return Err(GdriveError::Corrupted(
+            "File changed on the server during download".to_string(),
+        ));
+    }
+
+    file.seek(SeekFrom::Start(*position)).await?;
+    let stream = response.bytes_stream().map_err(io::Error::other);
+    let mut reader = tokio_util::io::StreamReader::new(stream);
+    let mut buffer = vec![0u8; 1024 * 1024];
+    while *position < end {
+        let bytes_read = reader.read(&mut buffer).await.map_err(network_error)?;
+        if bytes_read == 0 {
+            return Err(GdriveError::Network(format!(
+                "Connection closed at {} of {} bytes",
+                position, end
+            )));
+        }
+        let bytes_read = bytes_read.min((end - *position) as usize);
+        file.write_all(&buffer[..bytes_read]).await?;
+        *position += bytes_read as u64;
+        progress.advance(bytes_read as u64).await?;
+    }
+    Ok(true)
+}
+
+/// Writes `reader` into `output_path` through a `.part` file without resuming, for sources
+/// that cannot serve byte ranges. `read_error` tells what a failed read means, e.g.
+/// [`network_error`] for a response body.
+pub async fn save_stream<R: AsyncRead + Unpin>(
+    reader: R,
+    read_error: fn(io::Error) -> GdriveError,
+    total_size: u64,
+    output_path: &Path,
+    app: &AppHandle,
+    cancel: &CancellationToken,
+) -> Result<(), GdriveError> {
+    let mut file = File::create(part_path(output_path)).await?;
+    let progress = Progress::new(output_path, 0, total_size, app, cancel);
+    copy_with_progress(reader, read_error, &mut file, &progress).await?;
+    drop(file);
+    finish(output_path).await
+}
+
+/// Aggregates bytes written by one or more concurrent writers into a single
+/// `download:progress` stream, and holds them back to the [`RateLimit`].
+pub struct Progress<'a> {
+    app: &'a AppHandle,
+    cancel: &'a CancellationToken,
+    file_name: String,
+    total_size: u64,
+    downloaded: AtomicU64,
+    meter: Mutex<Meter>,
+}
+
+/// Windows the speed is measured and limited over.
+struct Meter {
+    speed_since: Instant,
+    speed_bytes: u64,
+    speed: u64,
+    limit: u64,
+    limit_since: Instant,
+    limit_bytes: u64,
+}
+
+impl Meter {
+    fn new() -> Self {
+        let now = Instant::now();
+        Meter {
+            speed_since: now,
+            speed_bytes: 0,
+            speed: 0,
+            limit: 0,
+            limit_since: now,
+            limit_bytes: 0,
+        }
+    }
+
+    /// Counts `bytes` and returns the current speed and how long to pause to stay under
+    /// `limit`.
+    fn record(&mut self, bytes: u64, limit: u64) -> (u64, Duration) {
+        let now = Instant::now();
+
+        self.speed_bytes += bytes;
+        let elapsed = now - self.speed_since;
+        if elapsed >= SPEED_WINDOW || self.speed == 0 {
+            self.speed = (self.speed_bytes as f64 / elapsed.as_secs_f64().max(0.001)) as u64;
+            if elapsed >= SPEED_WINDOW {
+                self.speed_since = now;
+                self.speed_bytes = 0;
+            }
+        }
+
+        // Start over whenever the limit changes, so a lower limit does not pay for bytes
+        // fetched at the old rate.
+        if limit != self.limit || now - self.limit_since >= SPEED_WINDOW * 5 {
+            self.limit = limit;
+            self.limit_since = now;
+            self.limit_bytes = 0;
+        }
+        if limit == 0 {
+            return (self.speed, Duration::ZERO);
+        }
+        self.limit_bytes += bytes;
+        let allowed = Duration::from_secs_f64(self.limit_bytes as f64 / limit as f64);
+        let pause = allowed.saturating_sub(now - self.limit_since);
+        if pause > Duration::ZERO {
+            // Report what the limiter lets through rather than the burst that came in.
+            self.speed = self.speed.min(limit);
+        }
+        (self.speed, pause)
+    }
+}
+
+impl<'a> Progress<'a> {
+    pub fn new(
+        output_path: &Path,
+        offset: u64,
+        total_size: u64,
+        app: &'a AppHandle,
+        cancel: &'a CancellationToken,
+    ) -> Self {
+        Self {
+            app,
+            cancel,
+            file_name: output_path
+                .to_string_lossy()
+                .split('/')
+                .next_back()
+                .unwrap_or_default()
+                .to_string(),
+            total_size,
+            downloaded: AtomicU64::new(offset),
+            meter: Mutex::new(Meter::new()),
+        }
+    }
+
+    /// Records `bytes` more, emits the new totals and waits out the bandwidth limit.
+    /// Returns the size reached, or [`GdriveError::Cancelled`] once the download is cancelled.
+    pub async fn advance(&self, bytes: u64) -> Result<u64, GdriveError> {
+        crate::cancel::check(self.cancel)?;
+        let downloaded = self.downloaded.fetch_add(bytes, Ordering::Relaxed) + bytes;
+
+        let limit = self.app.state::<RateLimit>().get();
+        let (speed, pause) = self
+            .meter
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .record(bytes, limit);
+        let percentage = if self.total_size > 0 {
+            (downloaded as f64 / self.total_size as f64) * 100.0
+        } else {
+            0.0
+        };
+
+        self.app
+            .emit(
+                "download:progress",
+                Some(DownloadProgress {
+                    download_bytes: downloaded,
+                    percentage,
+                    speed_bytes_per_sec: speed,
+                    file_name: self.file_name.clone(),
+                }),
+            )
+            .ok();
+
+        if !pause.is_zero() {
+            tokio::time::sleep(pause).await;
+        }
+        Ok(downloaded)
+    }
+}
+
+/// Appends `reader` to `file`, counting every chunk in `progress`, which starts at the bytes
+/// already on disk from an earlier attempt. Read errors go through `read_error`, write errors
+/// stay [`GdriveError::Io`]. Returns the size reached.
+async fn copy_with_progress<R: AsyncRead + Unpin>(
+    mut reader: R,
+    read_error: fn(io::Error) -> GdriveError,
+    file: &mut File,
+    progress: &Progress<'_>,
+) -> Result<u64, GdriveError> {
+    let mut downloaded = progress.downloaded.load(Ordering::Relaxed);
+
+    let mut buffer = vec![0u8; 4 * 1024 * 1024];
+    loop {
+        let bytes_read = reader.read(&mut buffer).await.map_err(read_error)?;
+        if bytes_read == 0 {
+            break;
+        }
+
+        file.write_all(&buffer[..bytes_read]).await?;
+        downloaded = progress.advance(bytes_read as u64).await?;
+    }
+
+    file.flush().await?;
+    Ok(downloaded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A meter whose windows started `elapsed` ago.
+    fn meter_since(elapsed: Duration) -> Meter {
+        let mut meter = Meter::new();
+        meter.speed_since -= elapsed;
+        meter.limit_since -= elapsed;
+        meter
+    }
+
+    #[test]
+    fn fresh_download_starts_at_zero() {
+        assert_eq!(
+            answer(0, None, StatusCode::OK, None, Some(500)),
+            Answer::Write {
+                offset: 0,
+                total_size: 500
+            }
+        );
+    }
+
+    #[test]
+    fn partial_content_resumes_the_part_file() {
+        assert_eq!(
+            answer(
+                200,
+                Some(500),
+                StatusCode::PARTIAL_CONTENT,
+                Some(500),
+                Some(300)
+            ),
+            Answer::Write {
+                offset: 200,
+                total_size: 500
+            }
+        );
+    }
+
+    #[test]
+    fn stale_validator_overwrites_the_part_file() {
+        // If-Range did not match, so the server ignored Range and sent the new file.
+        assert_eq!(
+            answer(200, Some(500), StatusCode::OK, None, Some(700)),
+            Answer::Write {
+                offset: 0,
+                total_size: 700
+            }
+        );
+    }
+
+    #[test]
+    fn changed_size_restarts() {
+        assert_eq!(
+            answer(
+                200,
+                Some(500),
+                StatusCode::PARTIAL_CONTENT,
+                Some(700),
+                Some(500)
+            ),
+            Answer::Restart
+        );
+    }
+
+    #[test]
+    fn range_not_satisfiable_finishes_a_complete_part_file() {
+        let status = StatusCode::RANGE_NOT_SATISFIABLE;
+        assert_eq!(
+            answer(500, Some(500), status, Some(500), None),
+            Answer::Complete
+        );
+        assert_eq!(
+            answer(200, Some(500), status, Some(100), None),
+            Answer::Restart
+        );
+    }
+
+    #[test]
+    fn error_statuses_fail() {
+        assert_eq!(
+            answer(0, None, StatusCode::RANGE_NOT_SATISFIABLE, None, None),
+            Answer::Failed
+        );
+        assert_eq!(
+            answer(200, Some(500), StatusCode::NOT_FOUND, None, None),
+            Answer::Failed
+        );
+    }
+
+    #[test]
+    fn meter_without_limit_never_pauses() {
+        let mut meter = meter_since(SPEED_WINDOW);
+        let (speed, pause) = meter.record(10 * 1024 * 1024, 0);
+        assert_eq!(pause, Duration::ZERO);
+        assert!(
+            speed > 9 * 1024 * 1024 && speed <= 10 * 1024 * 1024,
+            "{}",
+            speed
+        );
+    }
+
+    #[test]
+    fn meter_pauses_until_the_bytes_fit_the_limit() {
+        let mut meter = Meter::new();
+        meter.record(0, 1000);
+        let (speed, pause) = meter.record(3000, 1000);
+        assert!(
+            pause > Duration::from_millis(2900) && pause <= Duration::from_secs(3),
+            "{:?}",
+            pause
+        );
+        assert!(speed <= 1000, "{}", speed);
+    }
+
+    #[test]
+    fn meter_does_not_pause_under_the_limit() {
+        let mut meter = meter_since(Duration::from_secs(2));
+        meter.limit = 1000;
+        let (_, pause) = meter.record(1000, 1000);
+        assert_eq!(pause, Duration::ZERO);
+    }
+
+    #[test]
+    fn meter_starts_over_when_the_limit_changes() {
+        let mut meter = Meter::new();
+        meter.record(0, 100);
+        let (_, pause) = meter.record(10_000, 100);
+        assert!(pause > Duration::from_secs(90), "{:?}", pause);
+
+        // Raising the limit forgets the bytes counted against the old one.
+        let (_, pause) = meter.record(1000, 1_000_000);
+        assert!(pause <= Duration::from_millis(1), "{:?}", pause);
+    }
+}
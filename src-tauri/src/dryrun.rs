@@ -0,0 +1,352 @@
+use crate::delta;
+use crate::extract::{self, ArchiveEntry, ArchiveFormat};
+use crate::gdrive::GdriveError;
+use crate::source::{RemoteFile, UpdateSource};
+use crate::versions::PatchVersion;
+use bytes::Bytes;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::runtime::Handle;
+
+/// Bytes fetched per request while reading an archive's index remotely.
+const BLOCK_SIZE: u64 = 256 * 1024;
+
+/// What an update would change, worked out without writing anything.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DryRun {
+    pub from_version: Option<PatchVersion>,
+    pub to_version: Option<PatchVersion>,
+    /// `None` when the archive cannot be listed without downloading it, as with a tarball
+    /// that was not pre-downloaded.
+    pub files: Option<FileChanges>,
+    /// Profile files the update rewrites, in the order it rewrites them.
+    pub profile: Vec<ProfileDiff>,
+}
+
+/// Files of the patch folder by what the update does with them, as `/`-separated paths.
+#[derive(Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FileChanges {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    /// Files that exist and get different content, or whose content cannot be compared.
+    pub overwritten: Vec<String>,
+    pub unchanged: usize,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProfileDiff {
+    pub file_name: String,
+    pub lines: Vec<DiffLine>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DiffLine {
+    pub kind: DiffKind,
+    /// 1-based, in the current file for removed lines and in the new one for added lines.
+    pub line: usize,
+    pub text: String,
+}
+
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum DiffKind {
+    Added,
+    Removed,
+}
+
+/// Lists `file` from its index, fetching only the parts of it the index takes up. `None`
+/// for tarballs, which have no index, and when the source does not report the size.
+pub async fn list_remote(
+    source: Arc<dyn UpdateSource>,
+    file: &RemoteFile,
+) -> Result<Option<Vec<ArchiveEntry>>, GdriveError> {
+    let Some(len) = file.size else {
+        return Ok(None);
+    };
+    let magic = source.read_range(file, 0..len.min(8)).await?;
+    let format = match ArchiveFormat::read_magic(&magic[..])? {
+        Some(ArchiveFormat::TarZst) => return Ok(None),
+        Some(format) => format,
+        None => {
+            return Err(GdriveError::Corrupted(format!(
+                "Unknown archive format: {}",
+                file.name
+            )))
+        }
+    };
+
+    let mut reader = RangeReader {
+        source,
+        file: file.clone(),
+        len,
+        position: 0,
+        blocks: HashMap::new(),
+        runtime: Handle::current(),
+    };
+    tokio::task::spawn_blocking(move || match format {
+        ArchiveFormat::SevenZ => extract::list_seven_z(&mut reader, len),
+        _ => extract::list_zip(reader),
+    })
+    .await
+    .map_err(|e| GdriveError::Io(e.to_string()))?
+    .map(Some)
+}
+
+/// Sorts the files of an archive about to replace `patch_dir` by what happens to them.
+/// Same-sized files are told apart by CRC-32 when the archive stores one.
+pub async fn compare_archive(
+    entries: Vec<ArchiveEntry>,
+    patch_dir: PathBuf,
+) -> Result<FileChanges, GdriveError> {
+    tokio::task::spawn_blocking(move || {
+        let mut changes = FileChanges::default();
+        let mut published = HashSet::new();
+        for entry in entries.iter().filter(|entry| !entry.is_dir) {
+            let Some(path) = &entry.path else {
+                continue;
+            };
+            let name = path.to_string_lossy().replace('\\', "/");
+            let local = patch_dir.join(path);
+            match fs::metadata(&local) {
+                Ok(metadata) if metadata.is_file() => {
+                    let unchanged = metadata.len() == entry.size
+                        && match entry.crc32 {
+                            Some(crc32) => file_crc32(&local)? == crc32,
+                            None => false,
+                        };
+                    if unchanged {
+                        changes.unchanged += 1;
+                    } else {
+                        changes.overwritten.push(name.clone());
+                    }
+                }
+                _ => changes.added.push(name.clone()),
+            }
+            published.insert(name);
+        }
+        changes.removed = delta::local_files(&patch_dir)?
+            .into_iter()
+            .filter(|path| !published.contains(path))
+            .collect();
+        changes.removed.sort();
+        Ok(changes)
+    })
+    .await
+    .map_err(|e| GdriveError::Io(e.to_string()))?
+}
+
+/// The same for a delta update, from its plan against `patch_dir`.
+pub fn compare_plan(
+    manifest: &delta::PatchManifest,
+    plan: delta::Plan,
+    patch_dir: &Path,
+) -> FileChanges {
+    let (overwritten, added): (Vec<String>, Vec<String>) = plan
+        .download
+        .into_iter()
+        .map(|entry| entry.path)
+        .partition(|path| patch_dir.join(path).is_file());
+    FileChanges {
+        unchanged: manifest.files.len() - added.len() - overwritten.len(),
+        added,
+        removed: plan.remove,
+        overwritten,
+    }
+}
+
+fn file_crc32(path: &Path) -> Result<u32, GdriveError> {
+    let mut file = File::open(path)?;
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            return Ok(hasher.finalize());
+        }
+        hasher.update(&buffer[..read]);
+    }
+}
+
+/// The lines an update removes from and adds to a profile file. Runs of unchanged lines
+/// at both ends are skipped before comparing what is left.
+pub fn diff(file_name: &str, old: &str, new: &str) -> ProfileDiff {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+    let prefix = old
+        .iter()
+        .zip(&new)
+        .take_while(|(old, new)| old == new)
+        .count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(old, new)| old == new)
+        .count();
+    let old_rest = &old[prefix..old.len() - suffix];
+    let new_rest = &new[prefix..new.len() - suffix];
+
+    // Longest common subsequence of the lines from each position to the end.
+    let width = new_rest.len() + 1;
+    let mut common = vec![0usize; (old_rest.len() + 1) * width];
+    for i in (0..old_rest.len()).rev() {
+        for j in (0..new_rest.len()).rev() {
+            common[i * width + j] = if old_rest[i] == new_rest[j] {
+                common[(i + 1) * width + j + 1] + 1
+            } else {
+                common[(i + 1) * width + j].max(common[i * width + j + 1])
+            };
+        }
+    }
+
+    let mut lines = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    let mut push = |kind, index: usize, text: &str| {
+        lines.push(DiffLine {
+            kind,
+            line: prefix + index + 1,
+            text: text.to_string(),
+        })
+    };
+    while i < old_rest.len() || j < new_rest.len() {
+        if i < old_rest.len() && j < new_rest.len() && old_rest[i] == new_rest[j] {
+            i += 1;
+            j += 1;
+        } else if j == new_rest.len()
+            || (i < old_rest.len() && common[(i + 1) * width + j] >= common[i * width + j + 1])
+        {
+            push(DiffKind::Removed, i, old_rest[i]);
+            i += 1;
+        } else {
+            push(DiffKind::Added, j, new_rest[j]);
+            j += 1;
+        }
+    }
+
+    ProfileDiff {
+        file_name: file_name.to_string(),
+        lines,
+    }
+}
+
+/// Blocking [`Read`] and [`Seek`] over a remote file, fetching it in [`BLOCK_SIZE`] blocks
+/// as they are read. Meant for archive indexes, so fetched blocks are kept.
+struct RangeReader {
+    source: Arc<dyn UpdateSource>,
+    file: RemoteFile,
+    len: u64,
+    position: u64,
+    blocks: HashMap<u64, Bytes>,
+    runtime: Handle,
+}
+
+impl Read for RangeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+        let start = self.position / BLOCK_SIZE * BLOCK_SIZE;
+        if !self.blocks.contains_key(&start) {
+            let range = start..(start + BLOCK_SIZE).min(self.len);
+            let block = self
+                .runtime
+                .block_on(self.source.read_range(&self.file, range))
+                .map_err(io::Error::other)?;
+            self.blocks.insert(start, block);
+        }
+        let block = &self.blocks[&start];
+        let offset = (self.position - start) as usize;
+        if offset >= block.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("{} is shorter than reported", self.file.name),
+            ));
+        }
+        let len = buf.len().min(block.len() - offset);
+        buf[..len].copy_from_slice(&block[offset..offset + len]);
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+
+impl Seek for RangeReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        self.position = position.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Seek before the start of the file",
+            )
+        })?;
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(diff: &ProfileDiff) -> Vec<String> {
+        diff.lines
+            .iter()
+            .map(|line| {
+                let sign = match line.kind {
+                    DiffKind::Added => '+',
+                    DiffKind::Removed => '-',
+                };
+                format!("{}{}: {}", sign, line.line, line.text)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn same_content_has_no_lines() {
+        let content = "+RFAD_PATCH\n+Unofficial Patch\n";
+        assert!(diff("modlist.txt", content, content).lines.is_empty());
+    }
+
+    #[test]
+    fn numbers_lines_in_their_own_file() {
+        let old = "Skyrim.esm\nUpdate.esm\nOld.esp\nRequiem.esp\n";
+        let new = "Skyrim.esm\nUpdate.esm\nNew.esp\nPatch.esp\nRequiem.esp\n";
+        assert_eq!(
+            lines(&diff("loadorder.txt", old, new)),
+            ["-3: Old.esp", "+3: New.esp", "+4: Patch.esp"]
+        );
+    }
+
+    #[test]
+    fn moved_line_is_removed_and_added() {
+        let old = "A.esp\nB.esp\nC.esp\n";
+        let new = "B.esp\nC.esp\nA.esp\n";
+        assert_eq!(
+            lines(&diff("plugins.txt", old, new)),
+            ["-1: A.esp", "+3: A.esp"]
+        );
+    }
+
+    #[test]
+    fn empty_files() {
+        assert_eq!(lines(&diff("plugins.txt", "", "*A.esp\n")), ["+1: *A.esp"]);
+        assert_eq!(lines(&diff("plugins.txt", "*A.esp\n", "")), ["-1: *A.esp"]);
+    }
+
+    #[test]
+    fn ignores_line_endings() {
+        assert!(diff("plugins.txt", "A.esp\r\nB.esp\r\n", "A.esp\nB.esp")
+            .lines
+            .is_empty());
+    }
+}
@@ -0,0 +1,557 @@
+use crate::cancel;
+use crate::events::UnpackProgress;
+use crate::gdrive::GdriveError;
+use std::cmp::Reverse;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+use tauri::{AppHandle, Emitter};
+use tokio_util::sync::CancellationToken;
+use zip::ZipArchive;
+
+/// More threads than this only make the disk seek.
+const MAX_WORKERS: usize = 4;
+const COPY_BUFFER_SIZE: usize = 256 * 1024;
+
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+const EMPTY_ZIP_MAGIC: &[u8] = b"PK\x05\x06";
+const SEVEN_Z_MAGIC: &[u8] = b"7z\xBC\xAF\x27\x1C";
+const ZSTD_MAGIC: &[u8] = b"\x28\xB5\x2F\xFD";
+/// Names patch archives are published under, longest first for `.tar.zst`.
+const EXTENSIONS: [(&str, ArchiveFormat); 4] = [
+    (".tar.zst", ArchiveFormat::TarZst),
+    (".tzst", ArchiveFormat::TarZst),
+    (".7z", ArchiveFormat::SevenZ),
+    (".zip", ArchiveFormat::Zip),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Zip,
+    SevenZ,
+    /// A tarball compressed with zstd.
+    TarZst,
+}
+
+impl ArchiveFormat {
+    /// Detects the format from the first bytes of `path`, whatever its name.
+    pub fn detect(path: &Path) -> Result<Self, GdriveError> {
+        Self::read_magic(File::open(path)?)?.ok_or_else(|| {
+            GdriveError::Corrupted(format!("Unknown archive format: {}", path.display()))
+        })
+    }
+
+    /// Detects the format from the first bytes `reader` yields.
+    pub fn read_magic(reader: impl Read) -> Result<Option<Self>, GdriveError> {
+        let mut magic = Vec::with_capacity(SEVEN_Z_MAGIC.len());
+        reader
+            .take(SEVEN_Z_MAGIC.len() as u64)
+            .read_to_end(&mut magic)?;
+
+        Ok(
+            if magic.starts_with(ZIP_MAGIC) || magic.starts_with(EMPTY_ZIP_MAGIC) {
+                Some(ArchiveFormat::Zip)
+            } else if magic.starts_with(SEVEN_Z_MAGIC) {
+                Some(ArchiveFormat::SevenZ)
+            } else if magic.starts_with(ZSTD_MAGIC) {
+                Some(ArchiveFormat::TarZst)
+            } else {
+                None
+            },
+        )
+    }
+
+    /// The format a published file name promises, `None` for anything else.
+    pub fn from_name(name: &str) -> Option<Self> {
+        Some(split_extension(name)?.1)
+    }
+
+    /// `name` without its archive extension.
+    pub fn strip_extension(name: &str) -> Option<&str> {
+        Some(split_extension(name)?.0)
+    }
+}
+
+fn split_extension(name: &str) -> Option<(&str, ArchiveFormat)> {
+    let lower = name.to_lowercase();
+    EXTENSIONS.iter().find_map(|(extension, format)| {
+        lower
+            .ends_with(extension)
+            .then(|| (&name[..name.len() - extension.len()], *format))
+    })
+}
+
+/// A file or folder inside an archive.
+pub struct ArchiveEntry {
+    pub name: String,
+    /// Where the entry goes relative to the output folder, `None` when it would escape it or
+    /// is a link.
+    pub path: Option<PathBuf>,
+    pub size: u64,
+    pub is_dir: bool,
+    /// CRC-32 of the unpacked content, when the format stores one.
+    pub crc32: Option<u32>,
+}
+
+/// A patch archive on disk in any of the supported formats. The format is taken from the
+/// content, so a 7z archive saved as `update.zip` still opens.
+#[derive(Clone)]
+pub struct Archive {
+    path: PathBuf,
+    format: ArchiveFormat,
+}
+
+impl Archive {
+    /// Detects the format of `path` and reads its index, failing with
+    /// [`GdriveError::Corrupted`] when it is not an archive this launcher understands.
+    pub fn open(path: &Path) -> Result<Self, GdriveError> {
+        let format = ArchiveFormat::detect(path)?;
+        match format {
+            ArchiveFormat::Zip => {
+                open_zip(path)?;
+            }
+            ArchiveFormat::SevenZ => {
+                sevenz_rust::Archive::open(path).map_err(corrupted)?;
+            }
+            // A tarball has no index; the content is checked while it unpacks.
+            ArchiveFormat::TarZst => {}
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            format,
+        })
+    }
+
+    /// Lists the entries off the async runtime. A tarball has to be decompressed for that.
+    pub async fn entries(&self) -> Result<Vec<ArchiveEntry>, GdriveError> {
+        let archive = self.clone();
+        tokio::task::spawn_blocking(move || archive.entries_blocking())
+            .await
+            .map_err(|e| GdriveError::Io(e.to_string()))?
+    }
+
+    fn entries_blocking(&self) -> Result<Vec<ArchiveEntry>, GdriveError> {
+        match self.format {
+            ArchiveFormat::Zip => list_zip(File::open(&self.path)?),
+            ArchiveFormat::SevenZ => {
+                let mut file = File::open(&self.path)?;
+                let len = file.metadata()?.len();
+                list_seven_z(&mut file, len)
+            }
+            ArchiveFormat::TarZst => list_tar(&self.path),
+        }
+    }
+
+    /// Extracts the archive into `output` on blocking threads, reporting progress by bytes
+    /// written. Stops with [`GdriveError::Cancelled`] once `cancel` is cancelled.
+    pub async fn unpack(
+        &self,
+        output: &Path,
+        app: &AppHandle,
+        cancel: &CancellationToken,
+    ) -> Result<(), GdriveError> {
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        let archive = self.clone();
+        let output = output.to_path_buf();
+        let app = app.clone();
+        let cancel = cancel.clone();
+        tokio::task::spawn_blocking(move || match archive.format {
+            ArchiveFormat::Zip => unpack_zip(&archive.path, &output, &app, &cancel),
+            ArchiveFormat::SevenZ => unpack_seven_z(&archive.path, &output, &app, &cancel),
+            ArchiveFormat::TarZst => unpack_tar_zst(&archive.path, &output, &app, &cancel),
+        })
+        .await
+        .map_err(|e| GdriveError::Io(e.to_string()))?
+    }
+}
+
+/// Lists a zip archive from its central directory, reading nothing else.
+pub fn list_zip(reader: impl Read + Seek) -> Result<Vec<ArchiveEntry>, GdriveError> {
+    let mut archive = ZipArchive::new(reader).map_err(corrupted)?;
+    (0..archive.len())
+        .map(|index| {
+            let file = archive.by_index_raw(index).map_err(corrupted)?;
+            Ok(ArchiveEntry {
+                name: file.name().to_string(),
+                path: file.enclosed_name(),
+                size: file.size(),
+                is_dir: file.is_dir(),
+                crc32: Some(file.crc32()),
+            })
+        })
+        .collect()
+}
+
+/// Lists a 7z archive of `len` bytes from its header, reading nothing else.
+pub fn list_seven_z(
+    reader: &mut (impl Read + Seek),
+    len: u64,
+) -> Result<Vec<ArchiveEntry>, GdriveError> {
+    let archive = sevenz_rust::Archive::read(reader, len, &[]).map_err(corrupted)?;
+    Ok(archive
+        .files
+        .iter()
+        .map(|file| ArchiveEntry {
+            name: file.name().to_string(),
+            path: enclosed_path(file.name()),
+            size: file.size(),
+            is_dir: file.is_directory(),
+            crc32: file.has_crc.then_some(file.crc as u32),
+        })
+        .collect())
+}
+
+fn list_tar(path: &Path) -> Result<Vec<ArchiveEntry>, GdriveError> {
+    let mut entries = Vec::new();
+    let mut archive = open_tar(path)?;
+    for entry in archive.entries()? {
+        let entry = entry?;
+        let name = entry.path()?.to_string_lossy().into_owned();
+        let kind = entry.header().entry_type();
+        if is_tar_metadata(kind) {
+            continue;
+        }
+        entries.push(ArchiveEntry {
+            path: (kind.is_dir() || kind.is_file())
+                .then(|| enclosed_path(&name))
+                .flatten(),
+            name,
+            size: entry.size(),
+            is_dir: kind.is_dir(),
+            crc32: None,
+        });
+    }
+    Ok(entries)
+}
+
+fn open_zip(path: &Path) -> Result<ZipArchive<File>, GdriveError> {
+    ZipArchive::new(File::open(path)?).map_err(corrupted)
+}
+
+fn open_tar(path: &Path) -> Result<tar::Archive<impl Read>, GdriveError> {
+    Ok(tar::Archive::new(zstd::Decoder::new(File::open(path)?)?))
+}
+
+/// Records such as pax headers that describe other entries rather than carry a file.
+fn is_tar_metadata(kind: tar::EntryType) -> bool {
+    !kind.is_dir() && !kind.is_file() && !kind.is_symlink() && !kind.is_hard_link()
+}
+
+/// Resolves an entry name against the output folder, rejecting absolute paths and `..`.
+fn enclosed_path(name: &str) -> Option<PathBuf> {
+    if name.contains('\0') {
+        return None;
+    }
+    let mut path = PathBuf::new();
+    for component in Path::new(&name.replace('\\', "/")).components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    (!path.as_os_str().is_empty()).then_some(path)
+}
+
+fn unsafe_path(name: &str) -> GdriveError {
+    GdriveError::Corrupted(format!("Unsafe path in update archive: {}", name))
+}
+
+fn corrupted(e: impl std::fmt::Display) -> GdriveError {
+    GdriveError::Corrupted(format!("Corrupted update archive: {}", e))
+}
+
+/// A file to extract: its index in the archive, where it goes and its unpacked size.
+struct Entry {
+    index: usize,
+    path: PathBuf,
+    size: u64,
+}
+
+/// Spreads the entries over worker threads, each with its own handle on the archive.
+fn unpack_zip(
+    zip_path: &Path,
+    output: &Path,
+    app: &AppHandle,
+    cancel: &CancellationToken,
+) -> Result<(), GdriveError> {
+    let mut archive = open_zip(zip_path)?;
+
+    // Check every path and create the folders before any worker starts writing.
+    let mut entries = Vec::new();
+    for index in 0..archive.len() {
+        let file = archive.by_index_raw(index).map_err(corrupted)?;
+        let name = file
+            .enclosed_name()
+            .ok_or_else(|| unsafe_path(file.name()))?;
+        let path = output.join(name);
+        if file.is_dir() {
+            fs::create_dir_all(&path)?;
+        } else {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            entries.push(Entry {
+                index,
+                path,
+                size: file.size(),
+            });
+        }
+    }
+    // Largest first, so one big BSA does not end up alone at the end.
+    entries.sort_by_key(|entry| Reverse(entry.size));
+
+    let meter = Meter::new(app, cancel, entries.iter().map(|entry| entry.size).sum());
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let workers = thread::available_parallelism()
+        .map_or(1, |n| n.get())
+        .clamp(1, MAX_WORKERS)
+        .min(entries.len().max(1));
+
+    thread::scope(|scope| {
+        let handles: Vec<_> = (0..workers)
+            .map(|_| scope.spawn(|| work(zip_path, &entries, &next, &failed, &meter)))
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| {
+                handle
+                    .join()
+                    .unwrap_or_else(|_| Err(GdriveError::Io("Unpack worker panicked".into())))
+            })
+            .collect::<Result<Vec<()>, _>>()
+    })?;
+
+    meter.report(100.0);
+    Ok(())
+}
+
+/// Takes entries off the shared list until it is empty or another worker has failed.
+fn work(
+    zip_path: &Path,
+    entries: &[Entry],
+    next: &AtomicUsize,
+    failed: &AtomicBool,
+    meter: &Meter,
+) -> Result<(), GdriveError> {
+    let result = (|| {
+        let mut archive = open_zip(zip_path)?;
+        let mut buffer = vec![0; COPY_BUFFER_SIZE];
+        while !failed.load(Ordering::Relaxed) {
+            let Some(entry) = entries.get(next.fetch_add(1, Ordering::Relaxed)) else {
+                break;
+            };
+            let mut file = archive.by_index(entry.index).map_err(corrupted)?;
+            write_file(&mut file, &entry.path, &mut buffer, meter, true)?;
+        }
+        Ok(())
+    })();
+    if result.is_err() {
+        failed.store(true, Ordering::Relaxed);
+    }
+    result
+}
+
+/// 7z archives are usually solid, so entries are decoded in order on one thread.
+fn unpack_seven_z(
+    path: &Path,
+    output: &Path,
+    app: &AppHandle,
+    cancel: &CancellationToken,
+) -> Result<(), GdriveError> {
+    let mut reader =
+        sevenz_rust::SevenZReader::open(path, sevenz_rust::Password::empty()).map_err(corrupted)?;
+    let meter = Meter::new(
+        app,
+        cancel,
+        reader.archive().files.iter().map(|f| f.size()).sum(),
+    );
+    let mut buffer = vec![0; COPY_BUFFER_SIZE];
+
+    // The callback can only fail with the library's error type, so ours is kept aside.
+    let mut failure = None;
+    let result = reader.for_each_entries(|entry, data| {
+        let result = enclosed_path(entry.name())
+            .ok_or_else(|| unsafe_path(entry.name()))
+            .and_then(|name| {
+                let path = output.join(name);
+                if entry.is_directory() {
+                    Ok(fs::create_dir_all(&path)?)
+                } else {
+                    write_file(data, &path, &mut buffer, &meter, true)
+                }
+            });
+        match result {
+            Ok(()) => Ok(true),
+            Err(e) => {
+                failure = Some(e);
+                Ok(false)
+            }
+        }
+    });
+    if let Some(e) = failure {
+        return Err(e);
+    }
+    result.map_err(corrupted)?;
+
+    meter.report(100.0);
+    Ok(())
+}
+
+/// A tarball is one compressed stream, so progress follows the compressed bytes read.
+fn unpack_tar_zst(
+    path: &Path,
+    output: &Path,
+    app: &AppHandle,
+    cancel: &CancellationToken,
+) -> Result<(), GdriveError> {
+    let file = File::open(path)?;
+    let meter = Meter::new(app, cancel, file.metadata()?.len());
+    let mut archive = tar::Archive::new(zstd::Decoder::new(CountingReader {
+        inner: file,
+        meter: &meter,
+    })?);
+    let mut buffer = vec![0; COPY_BUFFER_SIZE];
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.to_string_lossy().into_owned();
+        let kind = entry.header().entry_type();
+        if is_tar_metadata(kind) {
+            continue;
+        }
+        if !kind.is_dir() && !kind.is_file() {
+            // Links could point anywhere on the disk.
+            return Err(unsafe_path(&name));
+        }
+        let path = output.join(enclosed_path(&name).ok_or_else(|| unsafe_path(&name))?);
+        if kind.is_dir() {
+            fs::create_dir_all(&path)?;
+        } else {
+            write_file(&mut entry, &path, &mut buffer, &meter, false)?;
+        }
+    }
+
+    meter.report(100.0);
+    Ok(())
+}
+
+/// Writes `data` to `path`, counting the bytes into `meter` when progress follows the
+/// output, and stops between reads once the operation is cancelled.
+fn write_file(
+    data: &mut dyn Read,
+    path: &Path,
+    buffer: &mut [u8],
+    meter: &Meter,
+    count: bool,
+) -> Result<(), GdriveError> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut outfile = File::create(path)?;
+    loop {
+        cancel::check(meter.cancel)?;
+        let read = data.read(buffer)?;
+        if read == 0 {
+            return Ok(());
+        }
+        outfile.write_all(&buffer[..read])?;
+        if count {
+            meter.add(read as u64);
+        }
+    }
+}
+
+/// Counts the bytes read from `inner` into a [`Meter`].
+struct CountingReader<'a, R> {
+    inner: R,
+    meter: &'a Meter<'a>,
+}
+
+impl<R: Read> Read for CountingReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.meter.add(read as u64);
+        Ok(read)
+    }
+}
+
+/// Bytes processed by all workers, emitted as `unpack:progress` at most once per 0.1%.
+struct Meter<'a> {
+    app: &'a AppHandle,
+    cancel: &'a CancellationToken,
+    total: u64,
+    written: AtomicU64,
+    /// Last reported progress in tenths of a percent.
+    reported: AtomicU64,
+}
+
+impl<'a> Meter<'a> {
+    fn new(app: &'a AppHandle, cancel: &'a CancellationToken, total: u64) -> Self {
+        Self {
+            app,
+            cancel,
+            total,
+            written: AtomicU64::new(0),
+            reported: AtomicU64::new(0),
+        }
+    }
+
+    fn add(&self, bytes: u64) {
+        let written = self.written.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        let permille = (written * 1000)
+            .checked_div(self.total)
+            .unwrap_or(1000)
+            .min(1000);
+        if self.reported.fetch_max(permille, Ordering::Relaxed) < permille {
+            self.report(permille as f64 / 10.0);
+        }
+    }
+
+    fn report(&self, percentage: f64) {
+        self.app
+            .emit("unpack:progress", UnpackProgress { percentage })
+            .ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn enclosed_path_keeps_nested_names() {
+        assert_eq!(
+            enclosed_path("meshes/armor/helmet.nif"),
+            Some(PathBuf::from("meshes").join("armor").join("helmet.nif"))
+        );
+        assert_eq!(
+            enclosed_path("./textures\\sky.dds"),
+            Some(PathBuf::from("textures").join("sky.dds"))
+        );
+    }
+
+    #[test]
+    fn enclosed_path_rejects_paths_that_escape_the_output() {
+        for name in [
+            "../evil.dll",
+            "meshes/../../evil.dll",
+            "..\\evil.dll",
+            "/etc/passwd",
+            "\\Windows\\evil.dll",
+            "evil\0.dll",
+        ] {
+            assert_eq!(enclosed_path(name), None, "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn enclosed_path_rejects_empty_names() {
+        assert_eq!(enclosed_path(""), None);
+        assert_eq!(enclosed_path("./"), None);
+    }
+}
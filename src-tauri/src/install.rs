@@ -0,0 +1,387 @@
+use crate::gdrive::GdriveError;
+use crate::write_log;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::sync::{Mutex, MutexGuard};
+
+/// Folder next to `mods` holding the previous install while an update runs. Kept on the same
+/// volume so that the patch folder can be moved in and out with a rename.
+const BACKUP_DIR_NAME: &str = "update_backup";
+/// Where a committed backup is moved before being deleted, so that a leftover
+/// `BACKUP_DIR_NAME` always means an update that did not finish.
+const STALE_BACKUP_DIR_NAME: &str = "update_backup.old";
+const PATCH_BACKUP_NAME: &str = "RFAD_PATCH";
+const PROFILE_BACKUP_NAME: &str = "profile";
+/// Lists the patch files backed up by [`Transaction::begin_files`]; without it the whole
+/// patch folder was moved aside.
+const TOUCHED_FILE_NAME: &str = "touched.json";
+/// Holds the mod folder the backup belongs to, which is not always the main patch.
+const PATCH_DIR_FILE_NAME: &str = "patch_dir.txt";
+
+/// Held by every command that installs, repairs or rolls back, since they all share one
+/// backup folder and each restores whatever backup it finds.
+static INSTALL: Mutex<()> = Mutex::const_new(());
+
+/// Waits for the install running in another command to finish.
+pub async fn lock() -> MutexGuard<'static, ()> {
+    INSTALL.lock().await
+}
+
+/// The same for quick commands, which fail rather than wait.
+pub fn try_lock() -> Result<MutexGuard<'static, ()>, GdriveError> {
+    INSTALL
+        .try_lock()
+        .map_err(|_| GdriveError::Io("Another update is running".into()))
+}
+
+/// A backup of the patch folder and profile files taken before an update touches them.
+/// Finish with [`Transaction::commit`] or [`Transaction::rollback`].
+pub struct Transaction {
+    base_dir: PathBuf,
+    backup_dir: PathBuf,
+    patch_dir: PathBuf,
+    profile_files: Vec<PathBuf>,
+}
+
+impl Transaction {
+    /// Moves `patch_dir` aside and copies `profile_files` into the backup folder under
+    /// `base_dir`. Fails when an update that never finished left its backup, which has to be
+    /// restored with [`Transaction::recover`] first.
+    pub fn begin(
+        base_dir: &Path,
+        patch_dir: &Path,
+        profile_files: &[PathBuf],
+    ) -> Result<Self, GdriveError> {
+        Self::start(base_dir, patch_dir, None, profile_files)
+    }
+
+    /// Like [`Transaction::begin`], but moves aside only `files` of the patch folder, given as
+    /// `/`-separated relative paths. Rolling back restores those files and nothing else.
+    pub fn begin_files(
+        base_dir: &Path,
+        patch_dir: &Path,
+        files: &[String],
+        profile_files: &[PathBuf],
+    ) -> Result<Self, GdriveError> {
+        Self::start(base_dir, patch_dir, Some(files), profile_files)
+    }
+
+    /// Restores the backup an interrupted update left under `base_dir`. Returns `false` when
+    /// there was none. `patch_dir` is used for backups that do not record their folder.
+    pub fn recover(
+        base_dir: &Path,
+        patch_dir: &Path,
+        profile_files: &[PathBuf],
+    ) -> Result<bool, GdriveError> {
+        let backup_dir = base_dir.join(BACKUP_DIR_NAME);
+        if !backup_dir.exists() {
+            return Ok(false);
+        }
+        let patch_dir = fs::read_to_string(backup_dir.join(PATCH_DIR_FILE_NAME))
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| patch_dir.to_path_buf());
+        let transaction = Self {
+            base_dir: base_dir.to_path_buf(),
+            backup_dir,
+            patch_dir,
+            profile_files: profile_files.to_vec(),
+        };
+        transaction.restore()?;
+        Ok(true)
+    }
+
+    fn start(
+        base_dir: &Path,
+        patch_dir: &Path,
+        files: Option<&[String]>,
+        profile_files: &[PathBuf],
+    ) -> Result<Self, GdriveError> {
+        let transaction = Self {
+            base_dir: base_dir.to_path_buf(),
+            backup_dir: base_dir.join(BACKUP_DIR_NAME),
+            patch_dir: patch_dir.to_path_buf(),
+            profile_files: profile_files.to_vec(),
+        };
+
+        if transaction.backup_dir.exists() {
+            return Err(GdriveError::Io(format!(
+                "An unfinished update left its backup in {}",
+                transaction.backup_dir.display()
+            )));
+        }
+        let _ = fs::remove_dir_all(base_dir.join(STALE_BACKUP_DIR_NAME));
+
+        fs::create_dir_all(transaction.backup_dir.join(PROFILE_BACKUP_NAME))?;
+        fs::write(
+            transaction.backup_dir.join(PATCH_DIR_FILE_NAME),
+            patch_dir.to_string_lossy().as_bytes(),
+        )?;
+        match files {
+            None => {
+                if transaction.patch_dir.exists() {
+                    fs::rename(&transaction.patch_dir, transaction.patch_backup())?;
+                }
+            }
+            Some(files) => {
+                let list = serde_json::to_vec(files).map_err(|e| GdriveError::Io(e.to_string()))?;
+                fs::write(transaction.backup_dir.join(TOUCHED_FILE_NAME), list)?;
+                for file in files {
+                    let source = transaction.patch_dir.join(file);
+                    if source.exists() {
+                        move_file(&source, &transaction.patch_backup().join(file))?;
+                    }
+                }
+            }
+        }
+        for file in &transaction.profile_files {
+            if file.exists() {
+                fs::copy(file, transaction.profile_backup(file))?;
+            }
+        }
+
+        Ok(transaction)
+    }
+
+    /// Keeps the new install and drops the backup.
+    pub fn commit(self) {
+        let stale = self.base_dir.join(STALE_BACKUP_DIR_NAME);
+        let result = fs::rename(&self.backup_dir, &stale).and_then(|_| fs::remove_dir_all(&stale));
+        if let Err(e) = result {
+            write_log(&format!(
+                "Failed to remove {}: {}",
+                self.backup_dir.display(),
+                e
+            ));
+        }
+    }
+
+    /// Puts the backed up install back after `error` and logs what was restored.
+    pub fn rollback(self, error: &GdriveError) {
+        write_log(&format!("Update failed, rolling back: {}", error));
+        if let Err(e) = self.restore() {
+            write_log(&format!(
+                "Rollback incomplete, backup kept in {}: {}",
+                self.backup_dir.display(),
+                e
+            ));
+        }
+    }
+
+    fn patch_backup(&self) -> PathBuf {
+        self.backup_dir.join(PATCH_BACKUP_NAME)
+    }
+
+    fn profile_backup(&self, file: &Path) -> PathBuf {
+        self.backup_dir
+            .join(PROFILE_BACKUP_NAME)
+            .join(file.file_name().unwrap_or_default())
+    }
+
+    fn restore(&self) -> Result<(), GdriveError> {
+        let mut restored = Vec::new();
+        let patch_backup = self.patch_backup();
+
+        match fs::read(self.backup_dir.join(TOUCHED_FILE_NAME)) {
+            Ok(list) => {
+                let files: Vec<String> =
+                    serde_json::from_slice(&list).map_err(|e| GdriveError::Io(e.to_string()))?;
+                for file in files {
+                    let target = self.patch_dir.join(&file);
+                    if target.exists() {
+                        fs::remove_file(&target)?;
+                    }
+                    let backup = patch_backup.join(&file);
+                    if backup.exists() {
+                        move_file(&backup, &target)?;
+                        restored.push(target.display().to_string());
+                    }
+                }
+            }
+            Err(_) => {
+                // Whatever is in the patch folder now was written by the failed update.
+                if self.patch_dir.exists() {
+                    fs::remove_dir_all(&self.patch_dir)?;
+                }
+                if patch_backup.exists() {
+                    fs::rename(&patch_backup, &self.patch_dir)?;
+                    restored.push(self.patch_dir.display().to_string());
+                }
+            }
+        }
+
+        for file in &self.profile_files {
+            let backup = self.profile_backup(file);
+            if backup.exists() {
+                replace_file(&backup, file)?;
+                restored.push(file.display().to_string());
+            }
+        }
+
+        fs::remove_dir_all(&self.backup_dir)?;
+        if restored.is_empty() {
+            write_log("Rolled back: nothing was installed before the update");
+        } else {
+            write_log(&format!("Rolled back: {}", restored.join(", ")));
+        }
+        Ok(())
+    }
+}
+
+/// Whether an update under `base_dir` has not finished or rolled back.
+pub fn backup_exists(base_dir: &Path) -> bool {
+    base_dir.join(BACKUP_DIR_NAME).exists()
+}
+
+fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::rename(from, to)
+}
+
+/// Copies `from` next to `to` and renames it over `to`, so `to` is never left half written.
+fn replace_file(from: &Path, to: &Path) -> io::Result<()> {
+    let mut name = to.file_name().unwrap_or_default().to_os_string();
+    name.push(".restore");
+    let tmp = to.with_file_name(name);
+    fs::copy(from, &tmp)?;
+    fs::rename(&tmp, to)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An install under a fresh base folder: `RFAD_PATCH` with `old.esp` and a profile file.
+    struct Install {
+        base_dir: PathBuf,
+        patch_dir: PathBuf,
+        profile: PathBuf,
+    }
+
+    impl Install {
+        fn new(name: &str) -> Self {
+            let base_dir =
+                std::env::temp_dir().join(format!("install-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&base_dir);
+            let patch_dir = base_dir.join("mods").join("RFAD_PATCH");
+            fs::create_dir_all(patch_dir.join("meshes")).unwrap();
+            fs::write(patch_dir.join("old.esp"), "old").unwrap();
+            fs::write(patch_dir.join("meshes").join("a.nif"), "old mesh").unwrap();
+            let profile = base_dir.join("plugins.txt");
+            fs::write(&profile, "*old.esp\n").unwrap();
+            Install {
+                base_dir,
+                patch_dir,
+                profile,
+            }
+        }
+
+        fn begin(&self) -> Transaction {
+            Transaction::begin(
+                &self.base_dir,
+                &self.patch_dir,
+                std::slice::from_ref(&self.profile),
+            )
+            .unwrap()
+        }
+
+        /// What an update writes once the transaction has started.
+        fn write_new(&self) {
+            fs::create_dir_all(&self.patch_dir).unwrap();
+            fs::write(self.patch_dir.join("new.esp"), "new").unwrap();
+            fs::write(&self.profile, "*new.esp\n").unwrap();
+        }
+
+        fn read(&self, path: &str) -> Option<String> {
+            fs::read_to_string(self.patch_dir.join(path)).ok()
+        }
+
+        fn profile(&self) -> String {
+            fs::read_to_string(&self.profile).unwrap()
+        }
+    }
+
+    impl Drop for Install {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.base_dir);
+        }
+    }
+
+    #[test]
+    fn begin_moves_the_patch_aside() {
+        let install = Install::new("begin");
+        let _transaction = install.begin();
+        assert!(!install.patch_dir.exists());
+        assert!(backup_exists(&install.base_dir));
+    }
+
+    #[test]
+    fn rollback_restores_the_previous_install() {
+        let install = Install::new("rollback");
+        let transaction = install.begin();
+        install.write_new();
+
+        transaction.rollback(&GdriveError::Io("test".into()));
+        assert_eq!(install.read("old.esp").as_deref(), Some("old"));
+        assert_eq!(install.read("meshes/a.nif").as_deref(), Some("old mesh"));
+        assert_eq!(install.read("new.esp"), None);
+        assert_eq!(install.profile(), "*old.esp\n");
+        assert!(!backup_exists(&install.base_dir));
+    }
+
+    #[test]
+    fn commit_keeps_the_new_install() {
+        let install = Install::new("commit");
+        let transaction = install.begin();
+        install.write_new();
+
+        transaction.commit();
+        assert_eq!(install.read("new.esp").as_deref(), Some("new"));
+        assert_eq!(install.read("old.esp"), None);
+        assert_eq!(install.profile(), "*new.esp\n");
+        assert!(!backup_exists(&install.base_dir));
+        assert!(!install.base_dir.join(STALE_BACKUP_DIR_NAME).exists());
+    }
+
+    #[test]
+    fn begin_files_restores_only_the_listed_files() {
+        let install = Install::new("files");
+        let files = ["meshes/a.nif".to_string(), "new.esp".to_string()];
+        let transaction =
+            Transaction::begin_files(&install.base_dir, &install.patch_dir, &files, &[]).unwrap();
+        assert_eq!(install.read("old.esp").as_deref(), Some("old"));
+        assert_eq!(install.read("meshes/a.nif"), None);
+
+        fs::write(install.patch_dir.join("meshes").join("a.nif"), "new mesh").unwrap();
+        fs::write(install.patch_dir.join("new.esp"), "new").unwrap();
+        fs::write(install.patch_dir.join("player.ini"), "player").unwrap();
+        transaction.rollback(&GdriveError::Io("test".into()));
+
+        assert_eq!(install.read("meshes/a.nif").as_deref(), Some("old mesh"));
+        assert_eq!(install.read("new.esp"), None);
+        assert_eq!(install.read("old.esp").as_deref(), Some("old"));
+        assert_eq!(install.read("player.ini").as_deref(), Some("player"));
+    }
+
+    #[test]
+    fn recover_restores_a_leftover_backup() {
+        let install = Install::new("recover");
+        // The launcher stopped mid-update: the transaction is never finished.
+        std::mem::forget(install.begin());
+        install.write_new();
+
+        let profile_files = [install.profile.clone()];
+        assert!(Transaction::begin(&install.base_dir, &install.patch_dir, &profile_files).is_err());
+        assert!(
+            Transaction::recover(&install.base_dir, Path::new("unused"), &profile_files).unwrap()
+        );
+        assert_eq!(install.read("old.esp").as_deref(), Some("old"));
+        assert_eq!(install.read("new.esp"), None);
+        assert_eq!(install.profile(), "*old.esp\n");
+        assert!(
+            !Transaction::recover(&install.base_dir, &install.patch_dir, &profile_files).unwrap()
+        );
+    }
+}
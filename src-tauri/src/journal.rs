@@ -0,0 +1,379 @@
+use crate::packages::InstalledPackage;
+use crate::versions::PatchVersion;
+use crate::{base_dir, install, write_log};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Records the update in progress, so that the next start knows when one was cut short.
+const JOURNAL_FILE_NAME: &str = "update_journal.json";
+
+/// What the interrupted update was installing.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(
+    tag = "kind",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum Target {
+    /// A patch archive; `version` is set for versioned archives.
+    Archive {
+        file_name: String,
+        version: Option<PatchVersion>,
+    },
+    /// The archive waiting in the staging folder.
+    Staged { version: PatchVersion },
+    /// A file-by-file update to the published manifest.
+    Delta { version: PatchVersion },
+    /// An optional package; `from_version` is the package's own. `previous` is its record
+    /// from before the install, which a rollback puts back.
+    Package {
+        id: String,
+        version: PatchVersion,
+        #[serde(default)]
+        previous: Option<InstalledPackage>,
+    },
+}
+
+impl Target {
+    /// The package this installs, `None` for the main patch.
+    fn package(&self) -> Option<&str> {
+        match self {
+            Target::Package { id, .. } => Some(id),
+            _ => None,
+        }
+    }
+}
+
+/// How an interrupted update is started over.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Resume {
+    /// Install the archive of this version again.
+    Version(PatchVersion),
+    /// Apply the pre-downloaded archive again.
+    Staged,
+    /// Install this package again.
+    Package(String),
+    /// Update to whatever is published now.
+    Latest,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Phase {
+    /// Nothing installed is touched yet.
+    Downloading,
+    /// The previous install is in the backup and the patch folder is being rewritten.
+    Installing,
+    /// The patch is in place and the profile files are being rewritten.
+    LoadOrder,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Journal {
+    pub target: Target,
+    pub phase: Phase,
+    /// The version a rollback brings back, `None` when no patch was installed.
+    pub from_version: Option<PatchVersion>,
+    pub started_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl Journal {
+    /// The main patch version installed, when this journal knows better than `version.txt`:
+    /// once the patch folder is touched, whatever it holds is half installed.
+    pub fn installed_version(&self) -> Option<Option<PatchVersion>> {
+        (self.phase != Phase::Downloading && self.target.package().is_none())
+            .then(|| self.from_version.clone())
+    }
+
+    /// What to run to finish the update. `staged` tells whether the pre-downloaded archive
+    /// is still there; without it, and for archives of no known version, the latest patch is
+    /// installed instead.
+    pub fn resume(&self, staged: bool) -> Resume {
+        match &self.target {
+            Target::Archive {
+                version: Some(version),
+                ..
+            } => Resume::Version(version.clone()),
+            Target::Staged { .. } if staged => Resume::Staged,
+            Target::Package { id, .. } => Resume::Package(id.clone()),
+            _ => Resume::Latest,
+        }
+    }
+
+    /// The package record a rollback puts back: the package and its record from before the
+    /// install, `None` when it was not installed.
+    pub fn package_record(&self) -> Option<(&str, Option<InstalledPackage>)> {
+        match &self.target {
+            Target::Package { id, previous, .. } => Some((id, previous.clone())),
+            _ => None,
+        }
+    }
+}
+
+fn journal_path(dir: &Path) -> PathBuf {
+    dir.join(JOURNAL_FILE_NAME)
+}
+
+pub fn read() -> Option<Journal> {
+    read_in(&base_dir())
+}
+
+fn read_in(dir: &Path) -> Option<Journal> {
+    let content = fs::read(journal_path(dir)).ok()?;
+    serde_json::from_slice(&content).ok()
+}
+
+/// Starts a journal for `target`. Resuming an interrupted update of the same patch or
+/// package keeps the version it started from, since its folder cannot be trusted any more.
+pub fn begin(target: Target, installed: Option<PatchVersion>) {
+    begin_in(&base_dir(), target, installed);
+}
+
+fn begin_in(dir: &Path, target: Target, installed: Option<PatchVersion>) {
+    let now = Utc::now();
+    let resumed = read_in(dir).filter(|journal| journal.target.package() == target.package());
+    let (from_version, started_at) = match resumed {
+        Some(journal) => (journal.from_version, journal.started_at),
+        None => (installed, now),
+    };
+    write(
+        dir,
+        &Journal {
+            target,
+            phase: Phase::Downloading,
+            from_version,
+            started_at,
+            updated_at: now,
+        },
+    );
+}
+
+pub fn set_phase(phase: Phase) {
+    set_phase_in(&base_dir(), phase);
+}
+
+fn set_phase_in(dir: &Path, phase: Phase) {
+    if let Some(mut journal) = read_in(dir) {
+        journal.phase = phase;
+        journal.updated_at = Utc::now();
+        write(dir, &journal);
+    }
+}
+
+/// Drops the journal once an update has ended either way, unless a rollback could not
+/// finish and left the backup behind.
+pub fn settle() {
+    settle_in(&base_dir());
+}
+
+fn settle_in(dir: &Path) {
+    if install::backup_exists(dir) {
+        write_log("Keeping the update journal: the backup was not restored");
+        return;
+    }
+    clear_in(dir);
+}
+
+pub fn clear() {
+    clear_in(&base_dir());
+}
+
+fn clear_in(dir: &Path) {
+    let path = journal_path(dir);
+    if path.exists() {
+        if let Err(e) = fs::remove_file(&path) {
+            write_log(&format!("Failed to remove {}: {}", path.display(), e));
+        }
+    }
+}
+
+/// Writes next to the journal and renames over it, so a crash leaves either the old or the
+/// new phase on disk.
+fn write(dir: &Path, journal: &Journal) {
+    let path = journal_path(dir);
+    let tmp = path.with_extension("json.tmp");
+    let result = serde_json::to_vec_pretty(journal)
+        .map_err(std::io::Error::other)
+        .and_then(|content| {
+            let mut file = File::create(&tmp)?;
+            file.write_all(&content)?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&tmp, &path));
+    if let Err(e) = result {
+        write_log(&format!("Failed to write {}: {}", path.display(), e));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(text: &str) -> PatchVersion {
+        PatchVersion::parse(text).unwrap()
+    }
+
+    fn journal_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("journal-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn delta(to: &str) -> Target {
+        Target::Delta {
+            version: version(to),
+        }
+    }
+
+    fn package(id: &str, previous: Option<&str>) -> Target {
+        Target::Package {
+            id: id.to_string(),
+            version: version("2.0"),
+            previous: previous.map(|previous| InstalledPackage {
+                folder: "RFAD_HOTFIX".to_string(),
+                version: version(previous),
+                above: None,
+                plugins: Vec::new(),
+            }),
+        }
+    }
+
+    fn journal(target: Target, phase: Phase) -> Journal {
+        Journal {
+            target,
+            phase,
+            from_version: Some(version("6.1")),
+            started_at: Utc::now(),
+            updated_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn begin_records_the_installed_version() {
+        let dir = journal_dir("begin");
+        begin_in(&dir, delta("6.2"), Some(version("6.1")));
+        let journal = read_in(&dir).unwrap();
+        assert_eq!(journal.phase, Phase::Downloading);
+        assert_eq!(journal.from_version, Some(version("6.1")));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn resuming_keeps_the_version_it_started_from() {
+        let dir = journal_dir("resume");
+        begin_in(&dir, delta("6.2"), Some(version("6.1")));
+        set_phase_in(&dir, Phase::Installing);
+        let started_at = read_in(&dir).unwrap().started_at;
+
+        // The half installed patch reports the new version by now.
+        begin_in(&dir, delta("6.2"), Some(version("6.2")));
+        let journal = read_in(&dir).unwrap();
+        assert_eq!(journal.from_version, Some(version("6.1")));
+        assert_eq!(journal.started_at, started_at);
+        assert_eq!(journal.phase, Phase::Downloading);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn another_target_does_not_inherit_the_version() {
+        let dir = journal_dir("cross");
+        begin_in(&dir, package("hotfix", Some("1.0")), Some(version("1.0")));
+        set_phase_in(&dir, Phase::Installing);
+
+        begin_in(&dir, delta("6.2"), Some(version("6.1")));
+        assert_eq!(read_in(&dir).unwrap().from_version, Some(version("6.1")));
+
+        begin_in(&dir, package("music", None), None);
+        assert_eq!(read_in(&dir).unwrap().from_version, None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn set_phase_needs_a_journal() {
+        let dir = journal_dir("phase");
+        set_phase_in(&dir, Phase::LoadOrder);
+        assert!(read_in(&dir).is_none());
+
+        begin_in(&dir, delta("6.2"), None);
+        set_phase_in(&dir, Phase::LoadOrder);
+        assert_eq!(read_in(&dir).unwrap().phase, Phase::LoadOrder);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn settle_keeps_the_journal_while_a_backup_is_left() {
+        let dir = journal_dir("settle");
+        begin_in(&dir, delta("6.2"), None);
+        fs::create_dir_all(dir.join("update_backup")).unwrap();
+        settle_in(&dir);
+        assert!(read_in(&dir).is_some());
+
+        fs::remove_dir_all(dir.join("update_backup")).unwrap();
+        settle_in(&dir);
+        assert!(read_in(&dir).is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn installed_version_once_the_patch_is_touched() {
+        assert_eq!(
+            journal(delta("6.2"), Phase::Downloading).installed_version(),
+            None
+        );
+        assert_eq!(
+            journal(delta("6.2"), Phase::Installing).installed_version(),
+            Some(Some(version("6.1")))
+        );
+        assert_eq!(
+            journal(package("hotfix", None), Phase::Installing).installed_version(),
+            None
+        );
+    }
+
+    #[test]
+    fn resumes_what_was_interrupted() {
+        let archive = |archive_version: Option<&str>| Target::Archive {
+            file_name: "RFAD_PATCH.zip".to_string(),
+            version: archive_version.map(version),
+        };
+        let staged = Target::Staged {
+            version: version("6.2"),
+        };
+        let resume = |target, staged| journal(target, Phase::Installing).resume(staged);
+
+        assert_eq!(
+            resume(archive(Some("6.0")), false),
+            Resume::Version(version("6.0"))
+        );
+        assert_eq!(resume(archive(None), false), Resume::Latest);
+        assert_eq!(resume(staged.clone(), true), Resume::Staged);
+        assert_eq!(resume(staged, false), Resume::Latest);
+        assert_eq!(resume(delta("6.2"), true), Resume::Latest);
+        assert_eq!(
+            resume(package("hotfix", None), false),
+            Resume::Package("hotfix".to_string())
+        );
+    }
+
+    #[test]
+    fn rollback_restores_the_package_record() {
+        let hotfix = journal(package("hotfix", Some("1.0")), Phase::Installing);
+        let (id, previous) = hotfix.package_record().unwrap();
+        assert_eq!(id, "hotfix");
+        assert_eq!(
+            previous.map(|previous| previous.version),
+            Some(version("1.0"))
+        );
+
+        let music = journal(package("music", None), Phase::Installing);
+        assert!(music.package_record().unwrap().1.is_none());
+        let update = journal(delta("6.2"), Phase::Installing);
+        assert!(update.package_record().is_none());
+    }
+}
@@ -1,28 +1,49 @@
+mod cache;
+mod cancel;
+mod checksum;
+mod config;
+mod delta;
+mod download;
+mod dryrun;
 mod events;
+mod extract;
 mod gdrive;
-
-use crate::events::{UnpackProgress, UpdateProgress, UpdateStatus};
+mod install;
+mod journal;
+mod packages;
+mod plugins;
+mod preflight;
+mod source;
+mod staging;
+mod streaming;
+mod versions;
+
+use crate::cache::RemoteData;
+use crate::config::ConfigState;
+use crate::events::{DownloadProgress, UnpackProgress, UpdateProgress, UpdateStatus};
+use crate::gdrive::GdriveError;
+use crate::plugins::PluginList;
+use crate::versions::{PatchVersion, UpdateCheck};
 use std::{
+    collections::{BTreeMap, HashMap},
     env, fs,
-    fs::File,
     fs::OpenOptions,
-    io::{Error, Read, Seek, SeekFrom, Write},
-    path::PathBuf,
-    time::{Duration, SystemTime},
+    io::{Error, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
 };
 use futures::StreamExt;
-use tauri::utils::mime_type::MimeType;
-use tauri::{AppHandle, Emitter, Manager};
+use source::{RemoteFile, UpdateSource};
+use tauri::{AppHandle, Emitter, Manager, State, WindowEvent};
 use tokio::io::AsyncWriteExt;
-use tokio::time::sleep;
-use zip::ZipArchive;
+use tokio_util::sync::CancellationToken;
 
-const FOLDER_ID: &str = "1JUOctbsugh2IIEUCWcBkupXYVYoJMg4G";
 const LOCAL_VERSION_FILE_NAME: &str = "version.txt";
 const REMOTE_VERSION_FILE_NAME: &str = "remote_version.txt";
 const LOCAL_UPDATE_FILE_NAME: &str = "update.zip";
-const MODS_JSON_FILE_NAME: &str = "launcher-mods.json";
 const PATCHES_JSON_FILE_NAME: &str = "launcher-patches.json";
+const DOWNLOAD_ATTEMPTS: u32 = 3;
 
 #[tauri::command]
 fn exe_dir() -> PathBuf {
@@ -231,42 +252,30 @@ fn update_game_settings(framerate: u32, voice: String) -> Result<(), String> {
     Ok(())
 }
 
-async fn unpack(mut archive: ZipArchive<File>, output: PathBuf, app: &AppHandle) {
-    sleep(Duration::from_millis(400)).await;
-    let total_files = archive.len();
-
-    for i in 0..total_files {
-        let mut file = archive.by_index(i).unwrap();
-        let outpath = output.join(file.enclosed_name().unwrap());
-
-        if file.is_dir() {
-            fs::create_dir_all(&outpath).unwrap();
-        } else {
-            if let Some(parent) = outpath.parent() {
-                fs::create_dir_all(parent).unwrap();
-            }
-            let mut outfile = File::create(&outpath).unwrap();
-            std::io::copy(&mut file, &mut outfile).unwrap();
-        }
-
-        let percentage = ((i + 1) as f64 / total_files as f64) * 100.0;
-        app.emit("unpack:progress", UnpackProgress { percentage })
-            .ok();
-    }
+async fn connect_and_list(
+    app: &AppHandle,
+) -> Result<(Arc<dyn UpdateSource>, Vec<RemoteFile>), GdriveError> {
+    let source = source::connect(app).await?;
+    let files = source.list_files().await?;
+    Ok((source, files))
 }
 
-async fn new_load_order() -> Result<String, ()> {
-    let drive = gdrive::GoogleDriveClient::new().await;
-    let files = drive.list_files(FOLDER_ID).await;
-    let (id, _, _) = files
-        .iter()
-        .find(|(_, name, _)| name == "modlist")
-        .ok_or(())?;
-    let txt = drive.load_text(id).await.map_err(|_| ())?;
+/// Fetches the published modlist, `None` when the source does not publish one.
+async fn new_load_order(
+    source: &dyn UpdateSource,
+    files: &[RemoteFile],
+) -> Result<Option<String>, GdriveError> {
+    let Some(file) = source::find_file(files, "modlist") else {
+        return Ok(None);
+    };
+    let txt = cache::with_fallback(cache::MODLIST_KEY, source.load_text(file))
+        .await?
+        .content;
+    let txt = remove_whitespace(&txt);
     if txt.is_empty() {
-        Err(())
+        Ok(None)
     } else {
-        Ok(txt)
+        Ok(Some(txt))
     }
 }
 
@@ -274,47 +283,58 @@ fn remove_whitespace(s: &str) -> String {
     s.trim().replace('\u{FEFF}', "")
 }
 
-fn update_modlist() {
-    let path = profile_dir().join("modlist.txt");
-    let mut file = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .open(&path)
-        .expect("cannot open modlist.txt");
-
-    let mut content = String::new();
-    file.read_to_string(&mut content).unwrap();
-
-    let new_content = format!("+RFAD_PATCH\n{}", content.replace("+RFAD_PATCH\n", ""));
-    file.seek(SeekFrom::Start(0)).unwrap();
-    file.write_all(new_content.as_bytes()).unwrap();
-    file.set_len(new_content.len() as u64).unwrap();
+fn modlist_content(
+    content: &str,
+    installed: &BTreeMap<String, packages::InstalledPackage>,
+) -> String {
+    let main = format!("+{}\n", packages::MAIN_FOLDER);
+    let new_content = format!("{}{}", main, content.replace(&main, ""));
+    packages::arrange_modlist(&new_content, installed)
 }
 
-async fn update_order(path: &PathBuf, new_list: &str, separator: &str) -> Result<(), Error> {
-    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
-    let mut content = String::new();
-    file.read_to_string(&mut content)?;
-
-    let mod_list: Vec<String> = if separator == "Requiem for the Indifferent.esp" {
-        new_list.lines().map(|l| l.to_string()).collect()
+/// A profile file as `(path, content now, content after the update)`.
+type ProfileChange = (PathBuf, Vec<u8>, Vec<u8>);
+
+/// The profile files [`update_load_order`] rewrites, leaving out the ones it would not
+/// change. Writes nothing. The main patch goes on top of `modlist.txt`; a package install
+/// only puts the installed packages in place.
+fn load_order_changes(
+    new_list: Option<&str>,
+    main_patch: bool,
+) -> Result<Vec<ProfileChange>, Error> {
+    let installed = packages::read_installed();
+    let mut changes = Vec::new();
+
+    let modlist_txt = profile_dir().join("modlist.txt");
+    let content = fs::read_to_string(&modlist_txt)?;
+    let updated = if main_patch {
+        modlist_content(&content, &installed)
     } else {
-        new_list.lines().map(|l| format!("*{}", l)).collect()
+        packages::arrange_modlist(&content, &installed)
     };
+    changes.push((modlist_txt, content.into_bytes(), updated.into_bytes()));
 
-    for entry in &mod_list {
-        content = content.replace(entry, "");
-    }
-
-    if let Some((head, tail)) = content.split_once(separator) {
-        let joined = mod_list.join("\n");
-        let updated = format!("{}\n{}\n{}{}", head.trim_end(), joined, separator, tail);
-        file.seek(SeekFrom::Start(0))?;
-        file.write_all(updated.as_bytes())?;
-        file.set_len(updated.len() as u64)?;
+    let new_plugins: Vec<String> = new_list
+        .map(|new_list| {
+            let new_list = PluginList::parse(new_list.as_bytes());
+            new_list.names().map(str::to_string).collect()
+        })
+        .unwrap_or_default();
+    // Only plugins.txt marks active plugins.
+    for (name, active) in [("plugins.txt", true), ("loadorder.txt", false)] {
+        let path = profile_dir().join(name);
+        if new_list.is_none() && !path.exists() {
+            continue;
+        }
+        let content = fs::read(&path)?;
+        let mut plugins = PluginList::parse(&content);
+        plugins.place_before(&new_plugins, "Requiem for the Indifferent.esp", active);
+        packages::add_plugins(&mut plugins, &installed, active);
+        changes.push((path, content, plugins.to_bytes()?));
     }
 
-    Ok(())
+    changes.retain(|(_, content, updated)| content != updated);
+    Ok(changes)
 }
 
 fn parse_framerate_limit(content: &str) -> Result<u32, String> {
@@ -415,37 +435,221 @@ fn replace_voice_locale(content: &str, voice: &str) -> Result<String, String> {
 }
 
 #[tauri::command]
-async fn download(app: AppHandle, id: &str, file_name: &str) -> Result<String, ()> {
-    let drive = gdrive::GoogleDriveClient::new().await;
-    let mime = if file_name.ends_with(".txt") {
-        MimeType::Txt
-    } else {
-        MimeType::OctetStream
+async fn download(app: AppHandle, id: &str, file_name: &str) -> Result<String, GdriveError> {
+    let operation = cancel::start(&app);
+    let source = source::connect(&app).await?;
+    let file = RemoteFile {
+        id: id.to_string(),
+        name: file_name.to_string(),
+        mime_type: if file_name.ends_with(".txt") {
+            source::GOOGLE_DOC_MIME_TYPE.to_string()
+        } else {
+            "application/octet-stream".to_string()
+        },
+        size: None,
+        modified_time: None,
+        md5: None,
     };
     let out_path = exe_dir().join(file_name);
-    let res = drive
-        .download_file(id, mime, out_path.to_str().unwrap(), app)
-        .await;
-    Ok(format!("Downloaded: {:?}", res))
+    source
+        .download_file(&file, &out_path, app, operation.token())
+        .await?;
+    Ok(format!("Downloaded: {}", out_path.display()))
+}
+
+/// Installed patch version, `None` before the first update.
+#[tauri::command]
+fn get_local_version() -> Option<PatchVersion> {
+    installed_version()
+}
+
+/// Compares the published patch version with the installed one. Falls back to the last
+/// published version seen when the source is unreachable.
+#[tauri::command]
+async fn check_for_update(app: AppHandle) -> Result<UpdateCheck, GdriveError> {
+    let remote = cache::with_fallback(cache::VERSION_KEY, fetch_remote_version(&app)).await?;
+    let version = PatchVersion::parse(&remote.content).ok_or_else(|| {
+        GdriveError::Decode(format!(
+            "Invalid published patch version {:?}",
+            remote.content
+        ))
+    })?;
+    Ok(UpdateCheck::new(
+        installed_version(),
+        version,
+        remote.cached_at,
+    ))
+}
+
+async fn fetch_remote_version(app: &AppHandle) -> Result<String, GdriveError> {
+    let (source, files) = connect_and_list(app).await?;
+
+    app.emit(
+        "update:progress",
+        UpdateProgress {
+            status: UpdateStatus::DownloadStarted as u8,
+        },
+    )
+    .ok();
+
+    let file = source::find_file(&files, "version")
+        .ok_or_else(|| GdriveError::NotFound("No version file in the update folder".into()))?;
+    let tmp = base_dir().join(REMOTE_VERSION_FILE_NAME);
+    source
+        .download_file(file, &tmp, app.clone(), &cancel::uncancellable())
+        .await?;
+    let ver = fs::read_to_string(&tmp);
+    let _ = fs::remove_file(&tmp);
+    Ok(ver?)
+}
+
+#[tauri::command]
+async fn update(app: AppHandle) -> Result<bool, GdriveError> {
+    let _install = install::lock().await;
+    let operation = cancel::start(&app);
+    let result = update_latest(&app, operation.token()).await;
+    journal::settle();
+    result
+}
+
+async fn update_latest(app: &AppHandle, cancel: &CancellationToken) -> Result<bool, GdriveError> {
+    let (source, files) = connect_and_list(app).await?;
+    if let Some(manifest) = source::find_file(&files, delta::MANIFEST_FILE_NAME) {
+        return update_delta(app, cancel, source.as_ref(), &files, manifest).await;
+    }
+
+    let zip = versions::latest_archive(&files)
+        .ok_or_else(|| GdriveError::NotFound("No patch archive in the update folder".into()))?;
+    install_archive(app, cancel, &source, &files, zip).await
 }
 
+/// What [`update`] would change in the patch folder and the profile. Reads archive indexes
+/// instead of downloading archives and writes nothing, not even the cache.
 #[tauri::command]
-fn get_local_version() -> String {
+async fn dry_run_update(app: AppHandle) -> Result<dryrun::DryRun, GdriveError> {
+    let (source, files) = connect_and_list(&app).await?;
+    let patch_dir = base_dir().join("mods").join(packages::MAIN_FOLDER);
+
+    let manifest = source::find_file(&files, delta::MANIFEST_FILE_NAME);
+    let (to_version, file_changes) = if let Some(manifest) = manifest {
+        let manifest = delta::parse_manifest(&source.load_text(manifest).await?)?;
+        let plan = delta::plan(
+            &manifest,
+            patch_dir.clone(),
+            delta::read_installed(&base_dir()),
+        )
+        .await?;
+        let changes = dryrun::compare_plan(&manifest, plan, &patch_dir);
+        (Some(manifest.version), Some(changes))
+    } else {
+        let zip = versions::latest_archive(&files)
+            .ok_or_else(|| GdriveError::NotFound("No patch archive in the update folder".into()))?;
+        let entries = match staged_archive(zip) {
+            Some(staged) => {
+                let archive = extract::Archive::open(&staging::archive_path(&staged))?;
+                Some(archive.entries().await?)
+            }
+            None => dryrun::list_remote(source.clone(), zip).await?,
+        };
+        let changes = match entries {
+            Some(entries) => Some(dryrun::compare_archive(entries, patch_dir).await?),
+            None => None,
+        };
+        (versions::archive_version(&zip.name), changes)
+    };
+
+    // Not through new_load_order, which would refresh the cache.
+    let new_list = match source::find_file(&files, "modlist") {
+        Some(file) => Some(remove_whitespace(&source.load_text(file).await?)),
+        None => None,
+    }
+    .filter(|list| !list.is_empty());
+    let profile = load_order_changes(new_list.as_deref(), true)?
+        .iter()
+        .map(|(path, content, updated)| {
+            let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+            let content = String::from_utf8_lossy(content);
+            dryrun::diff(&file_name, &content, &String::from_utf8_lossy(updated))
+        })
+        .collect();
+
+    Ok(dryrun::DryRun {
+        from_version: installed_version(),
+        to_version,
+        files: file_changes,
+        profile,
+    })
+}
+
+fn installed_version() -> Option<PatchVersion> {
+    // Whatever is in the patch folder now is half installed.
+    if let Some(version) = journal::read().and_then(|journal| journal.installed_version()) {
+        return version;
+    }
     let path = base_dir()
         .join("mods")
-        .join("RFAD_PATCH")
+        .join(packages::MAIN_FOLDER)
         .join(LOCAL_VERSION_FILE_NAME);
-    if !path.exists() {
-        return "NO_PATCH".into();
+    let content = fs::read_to_string(path).ok()?;
+    let version = PatchVersion::parse(&content);
+    if version.is_none() {
+        write_log(&format!("Ignoring invalid installed version {:?}", content));
     }
-    fs::read_to_string(path).unwrap_or_else(|_| "NO_PATCH".into())
+    version
+}
+
+#[tauri::command]
+async fn list_patch_versions(
+    app: AppHandle,
+) -> Result<Vec<versions::AvailableVersion>, GdriveError> {
+    let (_, files) = connect_and_list(&app).await?;
+    Ok(versions::available(&files, installed_version().as_ref()))
 }
 
+/// Installs the archive of `version`, replacing whatever is installed, even a newer one.
 #[tauri::command]
-async fn get_remote_version(app: AppHandle) -> String {
-    let drive = gdrive::GoogleDriveClient::new().await;
-    let files = drive.list_files(FOLDER_ID).await;
+async fn install_version(app: AppHandle, version: PatchVersion) -> Result<bool, GdriveError> {
+    let _install = install::lock().await;
+    let operation = cancel::start(&app);
+    let result = install_patch_version(&app, operation.token(), &version).await;
+    journal::settle();
+    result
+}
+
+async fn install_patch_version(
+    app: &AppHandle,
+    cancel: &CancellationToken,
+    version: &PatchVersion,
+) -> Result<bool, GdriveError> {
+    let (source, files) = connect_and_list(app).await?;
+    let zip = versions::find_archive(&files, version).ok_or_else(|| {
+        GdriveError::NotFound(format!("No archive for patch version {}", version))
+    })?;
 
+    match installed_version() {
+        Some(installed) if *version < installed => write_log(&format!(
+            "Downgrading patch from {} to {}",
+            installed, version
+        )),
+        installed => write_log(&format!(
+            "Installing patch {} over {}",
+            version,
+            installed.map_or("nothing".to_string(), |v| v.to_string())
+        )),
+    }
+    install_archive(app, cancel, &source, &files, zip).await
+}
+
+/// Installs the patch archive `zip`, taking it from the staging folder when it was already
+/// downloaded in the background.
+async fn install_archive(
+    app: &AppHandle,
+    cancel: &CancellationToken,
+    source: &Arc<dyn UpdateSource>,
+    files: &[RemoteFile],
+    zip: &RemoteFile,
+) -> Result<bool, GdriveError> {
+    // Sent before waiting, so that a background download being waited for shows up.
     app.emit(
         "update:progress",
         UpdateProgress {
@@ -453,31 +657,564 @@ async fn get_remote_version(app: AppHandle) -> String {
         },
     )
     .ok();
+    let _staging = staging::lock(app).await;
+    // Cancelling this update cancels the background download it waited for as well.
+    cancel::check(cancel)?;
+    staging::ensure_mo2_closed()?;
+    recover_backup()?;
+    journal::begin(
+        journal::Target::Archive {
+            file_name: zip.name.clone(),
+            version: versions::archive_version(&zip.name),
+        },
+        installed_version(),
+    );
+
+    let zip_path = match staged_archive(zip) {
+        Some(staged) => {
+            write_log(&format!("Using pre-downloaded {}", staged.file_name));
+            emit_downloaded(app);
+            staging::archive_path(&staged)
+        }
+        None => {
+            let config = app.state::<ConfigState>();
+            // Only zip archives can be read front to back as they arrive.
+            let streamable = extract::ArchiveFormat::from_name(&zip.name)
+                .is_none_or(|format| format == extract::ArchiveFormat::Zip);
+            if streamable && config.get().is_ok_and(|c| c.download.stream_unpack) {
+                match install_streamed(app, cancel, source, files, zip).await {
+                    Ok(()) => {
+                        staging::clear();
+                        return Ok(true);
+                    }
+                    Err(
+                        e @ (GdriveError::GameRunning(_)
+                        | GdriveError::Cancelled(_)
+                        | GdriveError::Preflight(_)),
+                    ) => return Err(e),
+                    Err(e) => {
+                        write_log(&format!(
+                            "Streaming install failed, downloading the archive instead: {}",
+                            e
+                        ));
+                        journal::set_phase(journal::Phase::Downloading);
+                    }
+                }
+            }
+            let zip_path = base_dir().join(LOCAL_UPDATE_FILE_NAME);
+            download_verified(app, cancel, source.as_ref(), files, zip, &zip_path).await?;
+            zip_path
+        }
+    };
 
-    if let Some((id, _, _)) = files.iter().find(|(_, name, _)| name == "version") {
-        let tmp = base_dir().join(REMOTE_VERSION_FILE_NAME);
-        drive
-            .download_file(id, MimeType::Txt, tmp.to_str().unwrap(), app.clone())
-            .await
+    app.emit(
+        "update:progress",
+        UpdateProgress {
+            status: UpdateStatus::DownloadFinished as u8,
+        },
+    )
+    .ok();
+
+    let new_list = new_load_order(source.as_ref(), files).await?;
+    let patch_dir = base_dir().join("mods").join(packages::MAIN_FOLDER);
+    install_downloaded(app, cancel, &zip_path, &patch_dir, new_list, true).await?;
+
+    let _ = fs::remove_file(&zip_path);
+    staging::clear();
+    Ok(true)
+}
+
+/// The pre-downloaded archive, if it is `zip` and complete.
+fn staged_archive(zip: &RemoteFile) -> Option<staging::StagedUpdate> {
+    staging::read().filter(|staged| {
+        staged.file_name == zip.name
+            && zip.size.is_none_or(|size| {
+                fs::metadata(staging::archive_path(staged)).is_ok_and(|m| m.len() == size)
+            })
+    })
+}
+
+/// Unpacks `zip` straight into the patch folder while it downloads, so the archive is
+/// never stored. Rolls back to the previous install if anything fails.
+async fn install_streamed(
+    app: &AppHandle,
+    cancel: &CancellationToken,
+    source: &Arc<dyn UpdateSource>,
+    files: &[RemoteFile],
+    zip: &RemoteFile,
+) -> Result<(), GdriveError> {
+    let expected = published_checksum(source.as_ref(), files, zip).await?;
+    let new_list = new_load_order(source.as_ref(), files).await?;
+    write_log(&format!("Downloading and unpacking {}", zip.name));
+
+    staging::ensure_mo2_closed()?;
+    // The central directory is read ahead from the end of the archive, the rest streams.
+    let mods_dir = base_dir().join("mods");
+    match dryrun::list_remote(source.clone(), zip).await? {
+        Some(entries) => preflight::check_archive(&entries, &mods_dir)?,
+        // Entry paths are checked while unpacking; the packed size is the least it will need.
+        None => preflight::check_space(&mods_dir, zip.size.unwrap_or(0))?,
+    }
+    let patch_dir = base_dir().join("mods").join(packages::MAIN_FOLDER);
+    journal::set_phase(journal::Phase::Installing);
+    let transaction = install::Transaction::begin(&base_dir(), &patch_dir, &profile_files())?;
+    let result = async {
+        fs::create_dir_all(&patch_dir)?;
+        app.emit(
+            "update:progress",
+            UpdateProgress {
+                status: UpdateStatus::UnpackStarted as u8,
+            },
+        )
+        .ok();
+        streaming::download_and_unpack(app, cancel, source.as_ref(), zip, expected, &patch_dir)
+            .await?;
+        for status in [UpdateStatus::DownloadFinished, UpdateStatus::UnpackFinished] {
+            app.emit(
+                "update:progress",
+                UpdateProgress {
+                    status: status as u8,
+                },
+            )
             .ok();
-        let ver = fs::read_to_string(&tmp).unwrap_or_else(|_| "NO_PATCH".into());
-        let _ = fs::remove_file(&tmp);
-        ver
-    } else {
-        "NO_PATCH".into()
+        }
+        update_load_order(new_list, true, app, cancel).await
     }
+    .await;
+
+    match result {
+        Ok(()) => {
+            transaction.commit();
+            Ok(())
+        }
+        Err(e) => {
+            transaction.rollback(&e);
+            Err(e)
+        }
+    }
+}
+
+/// Reports an archive that is already on disk as fully downloaded.
+fn emit_downloaded(app: &AppHandle) {
+    app.emit(
+        "download:progress",
+        Some(DownloadProgress {
+            file_name: LOCAL_UPDATE_FILE_NAME.to_string(),
+            download_bytes: 0,
+            percentage: 100.0,
+            speed_bytes_per_sec: 0,
+        }),
+    )
+    .ok();
 }
 
+/// Downloads `zip` to `zip_path` and checks it against the published checksums, retrying
+/// a corrupted download a few times.
+async fn download_verified(
+    app: &AppHandle,
+    cancel: &CancellationToken,
+    source: &dyn UpdateSource,
+    files: &[RemoteFile],
+    zip: &RemoteFile,
+    zip_path: &Path,
+) -> Result<(), GdriveError> {
+    let expected = published_checksum(source, files, zip).await?;
+    write_log(&format!(
+        "Downloading {} ({} bytes, modified {})",
+        zip.name,
+        zip.size.map_or("?".to_string(), |size| size.to_string()),
+        zip.modified_time
+            .map_or("?".to_string(), |time| time.to_rfc3339())
+    ));
+    if expected.is_none() {
+        write_log(&format!(
+            "No checksum published for {}, skipping verification",
+            zip.name
+        ));
+    }
+
+    for attempt in 1..=DOWNLOAD_ATTEMPTS {
+        source
+            .download_file(zip, zip_path, app.clone(), cancel)
+            .await?;
+        match checksum::verify_download(zip_path, zip, expected.clone()).await {
+            Ok(()) => return Ok(()),
+            Err(e) => {
+                write_log(&format!("Attempt {}/{}: {}", attempt, DOWNLOAD_ATTEMPTS, e));
+                let _ = fs::remove_file(zip_path);
+                if attempt == DOWNLOAD_ATTEMPTS {
+                    return Err(e);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The checksum the source publishes for `zip`, from its manifest or its metadata.
+async fn published_checksum(
+    source: &dyn UpdateSource,
+    files: &[RemoteFile],
+    zip: &RemoteFile,
+) -> Result<Option<checksum::Checksum>, GdriveError> {
+    let manifest = match source::find_file(files, checksum::MANIFEST_FILE_NAME) {
+        Some(file) => checksum::parse_manifest(&source.load_text(file).await?),
+        None => Default::default(),
+    };
+    Ok(checksum::expected_for(zip, &manifest))
+}
+
+/// Installs a downloaded archive into the mod folder `patch_dir`, rolling back to the
+/// previous install if anything fails. `main_patch` is unset for optional packages.
+async fn install_downloaded(
+    app: &AppHandle,
+    cancel: &CancellationToken,
+    archive_path: &Path,
+    patch_dir: &Path,
+    new_list: Option<String>,
+    main_patch: bool,
+) -> Result<(), GdriveError> {
+    staging::ensure_mo2_closed()?;
+    let archive = extract::Archive::open(archive_path)?;
+    preflight::check_archive(&archive.entries().await?, &base_dir().join("mods"))?;
+
+    journal::set_phase(journal::Phase::Installing);
+    let transaction = install::Transaction::begin(&base_dir(), patch_dir, &profile_files())?;
+    let installed = install_patch(
+        &archive,
+        patch_dir.to_path_buf(),
+        new_list,
+        main_patch,
+        app,
+        cancel,
+    )
+    .await;
+    match installed {
+        Ok(()) => {
+            transaction.commit();
+            Ok(())
+        }
+        Err(e) => {
+            transaction.rollback(&e);
+            Err(e)
+        }
+    }
+}
+
+/// Downloads the latest patch into the staging folder when it differs from the installed
+/// one, so that it can be applied later without waiting. Returns the staged version.
 #[tauri::command]
-async fn update(app: AppHandle) -> bool {
-    let drive = gdrive::GoogleDriveClient::new().await;
-    let files = drive.list_files(FOLDER_ID).await;
-    let (zip_id, _, _) = files
+async fn predownload(app: AppHandle) -> Result<Option<PatchVersion>, GdriveError> {
+    let operation = cancel::start(&app);
+    let (source, files) = connect_and_list(&app).await?;
+    if source::find_file(&files, delta::MANIFEST_FILE_NAME).is_some() {
+        // File-by-file updates are quick enough to run when the player asks for them.
+        return Ok(None);
+    }
+    let _staging = staging::lock(&app).await;
+
+    let version_file = source::find_file(&files, "version")
+        .ok_or_else(|| GdriveError::NotFound("No version file in the update folder".into()))?;
+    let content = source.load_text(version_file).await?;
+    let version = PatchVersion::parse(&content).ok_or_else(|| {
+        GdriveError::Decode(format!("Invalid published patch version {:?}", content))
+    })?;
+    // Never stage an older build over a newer install.
+    if versions::Comparison::of(installed_version().as_ref(), &version)
+        != versions::Comparison::Newer
+    {
+        return Ok(None);
+    }
+    let zip = versions::latest_archive(&files)
+        .ok_or_else(|| GdriveError::NotFound("No patch archive in the update folder".into()))?;
+    match staging::read() {
+        Some(staged) if staged.version == version => return Ok(Some(version)),
+        Some(_) => staging::clear(),
+        // Keeps an interrupted download of the same archive, which resumes below.
+        None => staging::clear_except(&zip.name),
+    }
+    fs::create_dir_all(staging::staging_dir())?;
+    let staged = staging::StagedUpdate {
+        version: version.clone(),
+        file_name: zip.name.clone(),
+        load_order: new_load_order(source.as_ref(), &files).await?,
+    };
+    write_log(&format!("Pre-downloading patch {}", version));
+    download_verified(
+        &app,
+        operation.token(),
+        source.as_ref(),
+        &files,
+        zip,
+        &staging::archive_path(&staged),
+    )
+    .await?;
+    staging::write(&staged)?;
+    Ok(Some(version))
+}
+
+/// Version of the pre-downloaded patch, if it is not installed yet.
+#[tauri::command]
+fn get_staged_update() -> Option<PatchVersion> {
+    staging::read()
+        .map(|staged| staged.version)
+        .filter(|version| installed_version().as_ref() != Some(version))
+}
+
+/// Installs the pre-downloaded patch without contacting the update source.
+#[tauri::command]
+async fn apply_staged_update(app: AppHandle) -> Result<bool, GdriveError> {
+    let _install = install::lock().await;
+    let operation = cancel::start(&app);
+    let result = install_staged(&app, operation.token()).await;
+    journal::settle();
+    result
+}
+
+async fn install_staged(app: &AppHandle, cancel: &CancellationToken) -> Result<bool, GdriveError> {
+    let _staging = staging::lock(app).await;
+    let staged =
+        staging::read().ok_or_else(|| GdriveError::NotFound("No pre-downloaded patch".into()))?;
+    staging::ensure_mo2_closed()?;
+    recover_backup()?;
+    write_log(&format!("Applying pre-downloaded patch {}", staged.version));
+    journal::begin(
+        journal::Target::Staged {
+            version: staged.version.clone(),
+        },
+        installed_version(),
+    );
+
+    app.emit(
+        "update:progress",
+        UpdateProgress {
+            status: UpdateStatus::DownloadStarted as u8,
+        },
+    )
+    .ok();
+    emit_downloaded(app);
+    app.emit(
+        "update:progress",
+        UpdateProgress {
+            status: UpdateStatus::DownloadFinished as u8,
+        },
+    )
+    .ok();
+
+    install_downloaded(
+        app,
+        cancel,
+        &staging::archive_path(&staged),
+        &base_dir().join("mods").join(packages::MAIN_FOLDER),
+        staged.load_order.clone(),
+        true,
+    )
+    .await?;
+    staging::clear();
+    Ok(true)
+}
+
+/// An update the launcher did not get to finish or roll back, e.g. because the PC turned off.
+#[tauri::command]
+fn get_interrupted_update(app: AppHandle) -> Option<journal::Journal> {
+    if cancel::is_running(&app) {
+        return None;
+    }
+    journal::read()
+}
+
+/// Starts the interrupted update over from the last complete install. Downloads pick up
+/// where they stopped.
+#[tauri::command]
+async fn resume_interrupted_update(app: AppHandle) -> Result<bool, GdriveError> {
+    let _install = install::lock().await;
+    let operation = cancel::start(&app);
+    let cancel = operation.token();
+    let journal =
+        journal::read().ok_or_else(|| GdriveError::NotFound("No interrupted update".into()))?;
+    write_log(&format!(
+        "Resuming interrupted update: {:?}",
+        journal.target
+    ));
+
+    let result = match journal.resume(staging::read().is_some()) {
+        journal::Resume::Version(version) => install_patch_version(&app, cancel, &version).await,
+        journal::Resume::Staged => install_staged(&app, cancel).await,
+        journal::Resume::Package(id) => install_optional_package(&app, cancel, &id).await,
+        journal::Resume::Latest => update_latest(&app, cancel).await,
+    };
+    journal::settle();
+    result
+}
+
+/// Puts back the install an interrupted update started from.
+#[tauri::command]
+fn rollback_interrupted_update() -> Result<(), GdriveError> {
+    let _install = install::try_lock()?;
+    staging::ensure_mo2_closed()?;
+    if !recover_backup()? {
+        write_log("Interrupted update had not changed anything yet");
+    }
+    journal::clear();
+    Ok(())
+}
+
+/// Restores the backup an interrupted update left behind, along with the package record
+/// when it was installing a package. Returns `false` when there was none. Runs before the
+/// next update starts its journal, which would lose what the interrupted one installed.
+fn recover_backup() -> Result<bool, GdriveError> {
+    let patch_dir = base_dir().join("mods").join(packages::MAIN_FOLDER);
+    if !install::Transaction::recover(&base_dir(), &patch_dir, &profile_files())? {
+        return Ok(false);
+    }
+    if let Some((id, previous)) = journal::read()
+        .as_ref()
+        .and_then(journal::Journal::package_record)
+    {
+        packages::revert(id, previous)?;
+    }
+    Ok(true)
+}
+
+/// Brings `mods/RFAD_PATCH` in line with the published manifest, downloading only the files
+/// that differ from it and deleting the ones it no longer lists.
+async fn update_delta(
+    app: &AppHandle,
+    cancel: &CancellationToken,
+    source: &dyn UpdateSource,
+    files: &[RemoteFile],
+    manifest: &RemoteFile,
+) -> Result<bool, GdriveError> {
+    let manifest = delta::parse_manifest(&source.load_text(manifest).await?)?;
+    let patch_dir = base_dir().join("mods").join(packages::MAIN_FOLDER);
+    // Plan against the last complete install, not one an interrupted update left behind.
+    recover_backup()?;
+    journal::begin(
+        journal::Target::Delta {
+            version: manifest.version.clone(),
+        },
+        installed_version(),
+    );
+    let plan = delta::plan(
+        &manifest,
+        patch_dir.clone(),
+        delta::read_installed(&base_dir()),
+    )
+    .await?;
+    write_log(&format!(
+        "Delta update to {}: {} files to download ({} bytes), {} to remove",
+        manifest.version,
+        plan.download.len(),
+        plan.download_size(),
+        plan.remove.len()
+    ));
+
+    let remote_files = published_entries(source, &plan.download).await?;
+    let new_list = new_load_order(source, files).await?;
+
+    staging::ensure_mo2_closed()?;
+    preflight::check_space(&base_dir().join("mods"), plan.download_size())?;
+    download_entries(
+        app,
+        cancel,
+        source,
+        &remote_files,
+        &plan.download,
+        &delta::download_dir(&base_dir()),
+    )
+    .await?;
+
+    // The version file is the publisher's when the manifest lists it, ours otherwise.
+    let write_version = !manifest
+        .files
         .iter()
-        .find(|(_, _, mime)| *mime == "application/x-zip-compressed")
-        .expect("zip not found");
+        .any(|entry| entry.path == LOCAL_VERSION_FILE_NAME);
+    let mut touched = plan.touched();
+    if write_version {
+        touched.push(LOCAL_VERSION_FILE_NAME.to_string());
+    }
+
+    journal::set_phase(journal::Phase::Installing);
+    let transaction =
+        install::Transaction::begin_files(&base_dir(), &patch_dir, &touched, &profile_files())?;
+    let result = async {
+        app.emit(
+            "update:progress",
+            UpdateProgress {
+                status: UpdateStatus::UnpackStarted as u8,
+            },
+        )
+        .ok();
+        delta::move_downloaded(&base_dir(), &patch_dir, &plan.download)?;
+        delta::remove_files(&patch_dir, &plan.remove)?;
+        if write_version {
+            fs::write(
+                patch_dir.join(LOCAL_VERSION_FILE_NAME),
+                manifest.version.to_string(),
+            )?;
+        }
+        app.emit("unpack:progress", UnpackProgress { percentage: 100.0 })
+            .ok();
+        app.emit(
+            "update:progress",
+            UpdateProgress {
+                status: UpdateStatus::UnpackFinished as u8,
+            },
+        )
+        .ok();
+
+        update_load_order(new_list, true, app, cancel).await
+    }
+    .await;
+
+    match result {
+        Ok(()) => transaction.commit(),
+        Err(e) => {
+            transaction.rollback(&e);
+            return Err(e);
+        }
+    }
+    delta::clear_downloads(&base_dir());
+    if let Err(e) = delta::save_installed(&base_dir(), &manifest, &patch_dir) {
+        write_log(&format!("Failed to save the installed manifest: {}", e));
+    }
+    Ok(true)
+}
+
+/// The published files of `entries`, keyed by their path in the manifest.
+async fn published_entries(
+    source: &dyn UpdateSource,
+    entries: &[delta::ManifestEntry],
+) -> Result<HashMap<String, RemoteFile>, GdriveError> {
+    let remote_files: HashMap<String, RemoteFile> = source
+        .list_tree(delta::FILES_DIR_NAME)
+        .await?
+        .into_iter()
+        .map(|file| (file.name.clone(), file))
+        .collect();
+    if let Some(missing) = entries
+        .iter()
+        .find(|entry| !remote_files.contains_key(&entry.path))
+    {
+        return Err(GdriveError::NotFound(format!(
+            "{} is listed in {} but not published",
+            missing.path,
+            delta::MANIFEST_FILE_NAME
+        )));
+    }
+    Ok(remote_files)
+}
 
-    let zip_path = base_dir().join(LOCAL_UPDATE_FILE_NAME);
+/// Downloads `entries` into `output_dir` one by one, checking each against its SHA-256.
+/// Files an earlier attempt already downloaded are kept when they check out.
+async fn download_entries(
+    app: &AppHandle,
+    cancel: &CancellationToken,
+    source: &dyn UpdateSource,
+    remote_files: &HashMap<String, RemoteFile>,
+    entries: &[delta::ManifestEntry],
+    output_dir: &Path,
+) -> Result<(), GdriveError> {
     app.emit(
         "update:progress",
         UpdateProgress {
@@ -485,14 +1222,47 @@ async fn update(app: AppHandle) -> bool {
         },
     )
     .ok();
-    let _ = drive
-        .download_file(
-            zip_id,
-            MimeType::OctetStream,
-            zip_path.to_str().unwrap(),
-            app.clone(),
+
+    let total_size: u64 = entries.iter().map(|entry| entry.size).sum();
+    let start_time = std::time::Instant::now();
+    let mut downloaded = 0;
+    for entry in entries {
+        cancel::check(cancel)?;
+        let file = &remote_files[&entry.path];
+        let output_path = output_dir.join(&entry.path);
+        if let Some(parent) = output_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let expected = checksum::Checksum::Sha256(entry.sha256.to_lowercase());
+        let downloaded_before = output_path.is_file()
+            && checksum::verify_download(&output_path, file, Some(expected.clone()))
+                .await
+                .is_ok();
+        if !downloaded_before {
+            source
+                .download_file(file, &output_path, app.clone(), cancel)
+                .await?;
+            checksum::verify_download(&output_path, file, Some(expected)).await?;
+        }
+
+        // Reported under the archive's name, which is what the progress bar follows.
+        downloaded += entry.size;
+        let elapsed = start_time.elapsed().as_secs_f64();
+        app.emit(
+            "download:progress",
+            Some(DownloadProgress {
+                file_name: LOCAL_UPDATE_FILE_NAME.to_string(),
+                download_bytes: downloaded,
+                percentage: downloaded as f64 / total_size.max(1) as f64 * 100.0,
+                speed_bytes_per_sec: if elapsed > 0.0 {
+                    (downloaded as f64 / elapsed) as u64
+                } else {
+                    0
+                },
+            }),
         )
-        .await;
+        .ok();
+    }
 
     app.emit(
         "update:progress",
@@ -501,15 +1271,116 @@ async fn update(app: AppHandle) -> bool {
         },
     )
     .ok();
+    Ok(())
+}
+
+/// The manifest of the installed version: the published one when it still matches, else
+/// the copy saved by the last file-by-file update.
+async fn installed_manifest(
+    source: &dyn UpdateSource,
+    files: &[RemoteFile],
+) -> Result<delta::PatchManifest, GdriveError> {
+    let installed =
+        installed_version().ok_or_else(|| GdriveError::NotFound("No patch installed".into()))?;
+    if let Some(file) = source::find_file(files, delta::MANIFEST_FILE_NAME) {
+        let manifest = delta::parse_manifest(&source.load_text(file).await?)?;
+        if manifest.version == installed {
+            return Ok(manifest);
+        }
+    }
+    delta::read_installed(&base_dir())
+        .filter(|manifest| manifest.version == installed)
+        .ok_or_else(|| {
+            GdriveError::NotFound(format!(
+                "No {} for the installed version {}",
+                delta::MANIFEST_FILE_NAME,
+                installed
+            ))
+        })
+}
+
+/// Hashes every file of `mods/RFAD_PATCH` against the manifest of the installed version.
+#[tauri::command]
+async fn verify_patch(app: AppHandle) -> Result<delta::IntegrityReport, GdriveError> {
+    let (source, files) = connect_and_list(&app).await?;
+    let manifest = installed_manifest(source.as_ref(), &files).await?;
+    let patch_dir = base_dir().join("mods").join(packages::MAIN_FOLDER);
+    let report = delta::verify(&manifest, patch_dir).await?;
+    write_log(&format!(
+        "Verified patch {}: {} missing, {} modified, {} extra",
+        report.version,
+        report.missing.len(),
+        report.modified.len(),
+        report.extra.len()
+    ));
+    Ok(report)
+}
+
+/// Downloads again the patch files that are missing or modified. Extra files are kept, they
+/// may be the player's own. Returns `false` when nothing was broken.
+#[tauri::command]
+async fn repair_patch(app: AppHandle) -> Result<bool, GdriveError> {
+    let _install = install::lock().await;
+    let operation = cancel::start(&app);
+    staging::ensure_mo2_closed()?;
+    let (source, files) = connect_and_list(&app).await?;
+    let manifest = installed_manifest(source.as_ref(), &files).await?;
+    let patch_dir = base_dir().join("mods").join(packages::MAIN_FOLDER);
+    recover_backup()?;
+    let broken = delta::verify(&manifest, patch_dir.clone())
+        .await?
+        .broken(&manifest);
+    if broken.is_empty() {
+        return Ok(false);
+    }
+    let size: u64 = broken.iter().map(|entry| entry.size).sum();
+    write_log(&format!(
+        "Repairing patch {}: {} files to download ({} bytes)",
+        manifest.version,
+        broken.len(),
+        size
+    ));
 
-    let patch_dir = base_dir().join("mods").join("RFAD_PATCH");
-    if patch_dir.exists() {
-        fs::remove_dir_all(&patch_dir).unwrap();
+    let remote_files = published_entries(source.as_ref(), &broken).await?;
+    preflight::check_space(&base_dir().join("mods"), size)?;
+    download_entries(
+        &app,
+        operation.token(),
+        source.as_ref(),
+        &remote_files,
+        &broken,
+        &delta::download_dir(&base_dir()),
+    )
+    .await?;
+
+    staging::ensure_mo2_closed()?;
+    let paths: Vec<String> = broken.iter().map(|entry| entry.path.clone()).collect();
+    let transaction = install::Transaction::begin_files(&base_dir(), &patch_dir, &paths, &[])?;
+    match delta::move_downloaded(&base_dir(), &patch_dir, &broken) {
+        Ok(()) => transaction.commit(),
+        Err(e) => {
+            transaction.rollback(&e);
+            return Err(e);
+        }
+    }
+    delta::clear_downloads(&base_dir());
+    if let Err(e) = delta::save_installed(&base_dir(), &manifest, &patch_dir) {
+        write_log(&format!("Failed to save the installed manifest: {}", e));
     }
-    fs::create_dir_all(&patch_dir).unwrap();
+    Ok(true)
+}
 
-    let zip_file = File::open(&zip_path).unwrap();
-    let archive = ZipArchive::new(zip_file).unwrap();
+/// Unpacks the patch into the empty `patch_dir` and puts it into the load order. Leaves
+/// cleaning up after a failure to the caller's [`install::Transaction`].
+async fn install_patch(
+    archive: &extract::Archive,
+    patch_dir: PathBuf,
+    new_list: Option<String>,
+    main_patch: bool,
+    app: &AppHandle,
+    cancel: &CancellationToken,
+) -> Result<(), GdriveError> {
+    fs::create_dir_all(&patch_dir)?;
     app.emit(
         "update:progress",
         UpdateProgress {
@@ -517,7 +1388,7 @@ async fn update(app: AppHandle) -> bool {
         },
     )
     .ok();
-    unpack(archive, patch_dir.clone(), &app).await;
+    archive.unpack(&patch_dir, app, cancel).await?;
     app.emit(
         "update:progress",
         UpdateProgress {
@@ -526,7 +1397,24 @@ async fn update(app: AppHandle) -> bool {
     )
     .ok();
 
-    let new_list = remove_whitespace(&new_load_order().await.unwrap_or_default());
+    update_load_order(new_list, main_patch, app, cancel).await
+}
+
+/// Profile files an update rewrites.
+fn profile_files() -> [PathBuf; 3] {
+    [
+        profile_dir().join("modlist.txt"),
+        profile_dir().join("plugins.txt"),
+        profile_dir().join("loadorder.txt"),
+    ]
+}
+
+async fn update_load_order(
+    new_list: Option<String>,
+    main_patch: bool,
+    app: &AppHandle,
+    cancel: &CancellationToken,
+) -> Result<(), GdriveError> {
     app.emit(
         "update:progress",
         UpdateProgress {
@@ -535,16 +1423,11 @@ async fn update(app: AppHandle) -> bool {
     )
     .ok();
 
-    update_modlist();
-    if !new_list.is_empty() {
-        let plugins_txt = profile_dir().join("plugins.txt");
-        let loadorder_txt = profile_dir().join("loadorder.txt");
-        update_order(&plugins_txt, &new_list, "*Requiem for the Indifferent.esp")
-            .await
-            .expect("Error updating plugins.txt");
-        update_order(&loadorder_txt, &new_list, "Requiem for the Indifferent.esp")
-            .await
-            .expect("Error updating loadorder.txt");
+    journal::set_phase(journal::Phase::LoadOrder);
+    for (path, _, content) in load_order_changes(new_list.as_deref(), main_patch)? {
+        cancel::check(cancel)?;
+        write_log(&format!("Rewriting {}", path.display()));
+        fs::write(&path, content)?;
     }
 
     app.emit(
@@ -554,19 +1437,188 @@ async fn update(app: AppHandle) -> bool {
         },
     )
     .ok();
+    Ok(())
+}
 
-    let _ = fs::remove_file(&zip_path);
-    true
+/// Optional packages published next to the patch, along with the installed ones. Falls
+/// back to the last published list when the source is unreachable.
+#[tauri::command]
+async fn list_packages(app: AppHandle) -> Result<Vec<packages::PackageStatus>, GdriveError> {
+    let mut sizes = HashMap::new();
+    let remote = cache::with_fallback(cache::PACKAGES_KEY, async {
+        let (source, files) = connect_and_list(&app).await?;
+        sizes = files
+            .iter()
+            .filter_map(|file| Some((file.name.clone(), file.size?)))
+            .collect();
+        load_packages_json(source.as_ref(), &files).await
+    })
+    .await?;
+    let published = packages::parse(&remote.content)?;
+    Ok(packages::statuses(
+        &published,
+        &packages::read_installed(),
+        |package| sizes.get(&package.archive).copied(),
+    ))
+}
+
+async fn load_packages_json(
+    source: &dyn UpdateSource,
+    files: &[RemoteFile],
+) -> Result<String, GdriveError> {
+    match source::find_file(files, packages::PACKAGES_JSON_FILE_NAME) {
+        Some(file) => source.load_text(file).await,
+        None => Ok("[]".into()),
+    }
 }
 
+/// Installs or updates the optional package `id` in its own mod folder.
 #[tauri::command]
-fn start_game() {
+async fn install_package(app: AppHandle, id: String) -> Result<bool, GdriveError> {
+    let _install = install::lock().await;
+    let operation = cancel::start(&app);
+    let result = install_optional_package(&app, operation.token(), &id).await;
+    journal::settle();
+    result
+}
+
+async fn install_optional_package(
+    app: &AppHandle,
+    cancel: &CancellationToken,
+    id: &str,
+) -> Result<bool, GdriveError> {
+    let (source, files) = connect_and_list(app).await?;
+    let package = packages::parse(&load_packages_json(source.as_ref(), &files).await?)?
+        .into_iter()
+        .find(|package| package.id == id)
+        .ok_or_else(|| {
+            GdriveError::NotFound(format!(
+                "No package {} in {}",
+                id,
+                packages::PACKAGES_JSON_FILE_NAME
+            ))
+        })?;
+    let archive = source::find_file(&files, &package.archive).ok_or_else(|| {
+        GdriveError::NotFound(format!(
+            "{} is listed in {} but not published",
+            package.archive,
+            packages::PACKAGES_JSON_FILE_NAME
+        ))
+    })?;
+
+    staging::ensure_mo2_closed()?;
+    // A resumed install takes `previous` from the journal, which this keeps.
+    recover_backup()?;
+    let mut installed = packages::read_installed();
+    // A resumed install already recorded the new version; the journal has the one before.
+    let previous = match journal::read().map(|journal| journal.target) {
+        Some(journal::Target::Package {
+            id: journal_id,
+            previous,
+            ..
+        }) if journal_id == id => previous,
+        _ => installed.get(id).cloned(),
+    };
+    journal::begin(
+        journal::Target::Package {
+            id: id.to_string(),
+            version: package.version.clone(),
+            previous: previous.clone(),
+        },
+        previous.as_ref().map(|previous| previous.version.clone()),
+    );
+    write_log(&format!(
+        "Installing package {} {} into {}",
+        id, package.version, package.folder
+    ));
+    app.emit(
+        "update:progress",
+        UpdateProgress {
+            status: UpdateStatus::DownloadStarted as u8,
+        },
+    )
+    .ok();
+    let archive_path = base_dir().join(LOCAL_UPDATE_FILE_NAME);
+    download_verified(app, cancel, source.as_ref(), &files, archive, &archive_path).await?;
+    app.emit(
+        "update:progress",
+        UpdateProgress {
+            status: UpdateStatus::DownloadFinished as u8,
+        },
+    )
+    .ok();
+
+    // Recorded before installing, so that the modlist update puts the package in place.
+    installed.insert(id.to_string(), packages::InstalledPackage::from(&package));
+    packages::write_installed(&installed)?;
+    let package_dir = base_dir().join("mods").join(&package.folder);
+    let result = install_downloaded(app, cancel, &archive_path, &package_dir, None, false).await;
+    let _ = fs::remove_file(&archive_path);
+    if let Err(e) = result {
+        if let Err(e) = packages::revert(id, previous) {
+            write_log(&format!("Failed to restore the package record: {}", e));
+        }
+        return Err(e);
+    }
+    Ok(true)
+}
+
+/// Deletes the installed package `id` and takes it out of the profile.
+#[tauri::command]
+fn remove_package(id: String) -> Result<(), GdriveError> {
+    let _install = install::try_lock()?;
+    staging::ensure_mo2_closed()?;
+    let mut installed = packages::read_installed();
+    let package = installed
+        .remove(&id)
+        .ok_or_else(|| GdriveError::NotFound(format!("Package {} is not installed", id)))?;
+    write_log(&format!("Removing package {} from {}", id, package.folder));
+
+    let package_dir = base_dir().join("mods").join(&package.folder);
+    if package_dir.exists() {
+        fs::remove_dir_all(&package_dir)?;
+    }
+    let modlist = profile_dir().join("modlist.txt");
+    if modlist.exists() {
+        let content = fs::read_to_string(&modlist)?;
+        fs::write(
+            &modlist,
+            packages::remove_from_modlist(&content, &package.folder),
+        )?;
+    }
+    packages::remove_plugins(&profile_dir().join("plugins.txt"), &package.plugins)?;
+    packages::remove_plugins(&profile_dir().join("loadorder.txt"), &package.plugins)?;
+    packages::write_installed(&installed)
+}
+
+/// Stops the running download or install. An install rolls back to what was there before.
+#[tauri::command]
+fn cancel_operation(app: AppHandle) -> bool {
+    cancel::cancel(&app)
+}
+
+/// Starts the game through MO2 and emits `game:exited` once MO2 closes.
+#[tauri::command]
+fn start_game(app: AppHandle) {
     let exe = base_dir().join("ModOrganizer.exe");
-    std::process::Command::new(exe)
-        .current_dir(&base_dir())
+    let mut child = std::process::Command::new(exe)
+        .current_dir(base_dir())
         .arg("moshortcut://:SKSE")
         .spawn()
         .expect("Failed to start game");
+
+    std::thread::spawn(move || {
+        let _ = child.wait();
+        app.emit("game:exited", ()).ok();
+    });
+}
+
+/// Waits for `child` on a thread of its own, so that it does not linger as a zombie once it
+/// exits.
+fn reap(mut child: std::process::Child) {
+    std::thread::spawn(move || {
+        let _ = child.wait();
+    });
 }
 
 #[tauri::command]
@@ -574,19 +1626,21 @@ fn open_explorer() {
     let base = base_dir();
     let parent = base.parent().expect("no parent dir");
 
-    std::process::Command::new("explorer")
+    let child = std::process::Command::new("explorer")
         .arg(parent)
         .spawn()
         .expect("Failed to open explorer");
+    reap(child);
 }
 
 #[tauri::command]
 fn open_mo2() {
     let exe = base_dir().join("ModOrganizer.exe");
-    std::process::Command::new(exe)
-        .current_dir(&base_dir())
+    let child = std::process::Command::new(exe)
+        .current_dir(base_dir())
         .spawn()
         .expect("Failed to start MO2");
+    reap(child);
 }
 
 // #[tauri::command]
@@ -608,24 +1662,37 @@ fn open_mo2() {
 // }
 
 #[tauri::command]
-async fn load_json_patches(app: AppHandle) -> String {
-    let drive = gdrive::GoogleDriveClient::new().await;
-    let files = drive.list_files(FOLDER_ID).await;
+async fn load_json_patches(app: AppHandle) -> Result<RemoteData, GdriveError> {
+    cache::with_fallback(cache::PATCHES_KEY, fetch_json_patches(&app)).await
+}
 
-    if let Some((id, _, _)) = files
-        .iter()
-        .find(|(_, name, _)| name == PATCHES_JSON_FILE_NAME)
-    {
+async fn fetch_json_patches(app: &AppHandle) -> Result<String, GdriveError> {
+    let (source, files) = connect_and_list(app).await?;
+
+    if let Some(file) = source::find_file(&files, PATCHES_JSON_FILE_NAME) {
         let tmp = base_dir().join(PATCHES_JSON_FILE_NAME);
-        drive
-            .download_file(id, MimeType::Json, tmp.to_str().unwrap(), app.clone())
-            .await
-            .ok();
-        let json = fs::read_to_string(&tmp).unwrap_or_else(|_| "[]".into());
+        source
+            .download_file(file, &tmp, app.clone(), &cancel::uncancellable())
+            .await?;
+        let json = fs::read_to_string(&tmp);
         let _ = fs::remove_file(&tmp);
-        json
+        Ok(json?)
     } else {
-        "[]".into()
+        Ok("[]".into())
+    }
+}
+
+/// Sets the download bandwidth limit, 0 for none. Applies to downloads already running.
+#[tauri::command]
+fn set_download_limit(limit: State<'_, download::RateLimit>, bytes_per_sec: u64) {
+    limit.set(bytes_per_sec);
+}
+
+#[tauri::command]
+fn get_config_errors(config: State<'_, ConfigState>) -> Vec<String> {
+    match &config.0 {
+        Ok(_) => vec![],
+        Err(errors) => errors.clone(),
     }
 }
 
@@ -680,7 +1747,7 @@ fn start_new_launcher() {
 }
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
-pub fn run(args: Vec<String>) {
+pub fn run() {
     let old_launcher_path = exe_dir().join("old-launcher.exe");
     if old_launcher_path.exists() {
         fs::remove_file(old_launcher_path).expect("Failed to remove old launcher");
@@ -688,10 +1755,24 @@ pub fn run(args: Vec<String>) {
 
     tauri::Builder::default()
         .plugin(tauri_plugin_opener::init())
+        .manage(config::load())
+        .manage(source::SourceState::default())
+        .manage(download::RateLimit::default())
+        .manage(cancel::Operations::default())
+        .manage(staging::StagingLock::default())
+        .on_window_event(|window, event| {
+            // Closing mid-update would leave the patch half installed: roll back first.
+            if let WindowEvent::CloseRequested { api, .. } = event {
+                if cancel::cancel_and_exit(window.app_handle()) {
+                    api.prevent_close();
+                }
+            }
+        })
         .invoke_handler(tauri::generate_handler![
             download,
             get_local_version,
-            get_remote_version,
+            check_for_update,
+            dry_run_update,
             update,
             start_game,
             open_explorer,
@@ -703,7 +1784,23 @@ pub fn run(args: Vec<String>) {
             load_json_patches,
             update_launcher,
             exe_dir,
-            start_new_launcher
+            start_new_launcher,
+            get_config_errors,
+            list_patch_versions,
+            verify_patch,
+            list_packages,
+            install_package,
+            remove_package,
+            repair_patch,
+            install_version,
+            predownload,
+            get_staged_update,
+            apply_staged_update,
+            set_download_limit,
+            cancel_operation,
+            get_interrupted_update,
+            resume_interrupted_update,
+            rollback_interrupted_update
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
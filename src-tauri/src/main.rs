@@ -1,11 +1,6 @@
 // Prevents additional console window on Windows in release, DO NOT REMOVE!!
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
-use std::env;
-
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    println!("args: {:?}", args);
-    let args = &args[1..];
-    rfad_launcher_lib::run(Vec::from(args))
+    rfad_launcher_lib::run()
 }
@@ -0,0 +1,371 @@
+use crate::base_dir;
+use crate::gdrive::GdriveError;
+use crate::plugins::PluginList;
+use crate::versions::{Comparison, PatchVersion};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Component, Path};
+
+/// Lists the optional packages published next to the main patch, e.g.
+/// `[{ "id": "hotfix", "name": "Хотфикс", "folder": "RFAD_HOTFIX", "version": "1.2",
+/// "archive": "RFAD_HOTFIX-1.2.zip", "above": "RFAD_PATCH", "plugins": ["RFAD_Hotfix.esp"] }]`.
+pub const PACKAGES_JSON_FILE_NAME: &str = "launcher-packages.json";
+/// The packages the launcher installed, in `MO2/`.
+const INSTALLED_FILE_NAME: &str = "installed-packages.json";
+/// Mod folder of the main patch, which no package may take over.
+pub const MAIN_FOLDER: &str = "RFAD_PATCH";
+
+/// An optional package as published in [`PACKAGES_JSON_FILE_NAME`].
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Package {
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    /// Mod folder under `mods/` the archive is unpacked into.
+    pub folder: String,
+    pub version: PatchVersion,
+    /// Archive in the update folder.
+    pub archive: String,
+    /// Mod the package is placed right above in `modlist.txt`, so that it wins conflicts
+    /// with it. On top of the list when unset or not found.
+    #[serde(default)]
+    pub above: Option<String>,
+    /// Plugins to enable at the end of the load order.
+    #[serde(default)]
+    pub plugins: Vec<String>,
+}
+
+/// What the launcher needs to keep a package in the profile after it was installed, even
+/// when the update source cannot be reached.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InstalledPackage {
+    pub folder: String,
+    pub version: PatchVersion,
+    pub above: Option<String>,
+    pub plugins: Vec<String>,
+}
+
+impl From<&Package> for InstalledPackage {
+    fn from(package: &Package) -> Self {
+        InstalledPackage {
+            folder: package.folder.clone(),
+            version: package.version.clone(),
+            above: package.above.clone(),
+            plugins: package.plugins.clone(),
+        }
+    }
+}
+
+/// A package as shown in the packages window: published, installed, or both.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PackageStatus {
+    pub id: String,
+    pub name: String,
+    pub description: Option<String>,
+    pub installed: Option<PatchVersion>,
+    /// `None` for an installed package that is no longer published.
+    pub remote: Option<PatchVersion>,
+    pub comparison: Option<Comparison>,
+    pub size: Option<u64>,
+}
+
+pub fn parse(content: &str) -> Result<Vec<Package>, GdriveError> {
+    let packages: Vec<Package> = serde_json::from_str(content.trim_start_matches('\u{FEFF}'))
+        .map_err(|e| GdriveError::Decode(format!("Invalid {}: {}", PACKAGES_JSON_FILE_NAME, e)))?;
+
+    let mut ids = HashSet::new();
+    let mut folders = HashSet::new();
+    for package in &packages {
+        if !ids.insert(package.id.as_str()) {
+            return Err(GdriveError::Decode(format!(
+                "Duplicate package id in {}: {}",
+                PACKAGES_JSON_FILE_NAME, package.id
+            )));
+        }
+        if !is_mod_folder(&package.folder)
+            || package.folder.eq_ignore_ascii_case(MAIN_FOLDER)
+            || !folders.insert(package.folder.to_lowercase())
+        {
+            return Err(GdriveError::Corrupted(format!(
+                "Invalid folder for package {} in {}: {}",
+                package.id, PACKAGES_JSON_FILE_NAME, package.folder
+            )));
+        }
+    }
+    Ok(packages)
+}
+
+/// A single folder name, so that a package cannot reach outside `mods/`.
+fn is_mod_folder(folder: &str) -> bool {
+    let mut components = Path::new(folder).components();
+    matches!(components.next(), Some(Component::Normal(_))) && components.next().is_none()
+}
+
+/// Installed packages by id.
+pub fn read_installed() -> BTreeMap<String, InstalledPackage> {
+    fs::read(base_dir().join(INSTALLED_FILE_NAME))
+        .ok()
+        .and_then(|content| serde_json::from_slice(&content).ok())
+        .unwrap_or_default()
+}
+
+/// Puts the record of package `id` back to `previous` after an install was rolled back,
+/// dropping it when the package was not installed before.
+pub fn revert(id: &str, previous: Option<InstalledPackage>) -> Result<(), GdriveError> {
+    let mut installed = read_installed();
+    match previous {
+        Some(previous) => {
+            installed.insert(id.to_string(), previous);
+        }
+        None => {
+            if installed.remove(id).is_none() {
+                return Ok(());
+            }
+        }
+    }
+    write_installed(&installed)
+}
+
+pub fn write_installed(installed: &BTreeMap<String, InstalledPackage>) -> Result<(), GdriveError> {
+    let content =
+        serde_json::to_vec_pretty(installed).map_err(|e| GdriveError::Io(e.to_string()))?;
+    Ok(fs::write(base_dir().join(INSTALLED_FILE_NAME), content)?)
+}
+
+/// Merges the published packages with the installed ones, published first.
+pub fn statuses(
+    published: &[Package],
+    installed: &BTreeMap<String, InstalledPackage>,
+    size: impl Fn(&Package) -> Option<u64>,
+) -> Vec<PackageStatus> {
+    let mut statuses: Vec<_> = published
+        .iter()
+        .map(|package| {
+            let version = installed.get(&package.id).map(|i| i.version.clone());
+            PackageStatus {
+                id: package.id.clone(),
+                name: package.name.clone(),
+                description: package.description.clone(),
+                comparison: Some(Comparison::of(version.as_ref(), &package.version)),
+                installed: version,
+                remote: Some(package.version.clone()),
+                size: size(package),
+            }
+        })
+        .collect();
+    statuses.extend(
+        installed
+            .iter()
+            .filter(|(id, _)| !published.iter().any(|package| package.id == **id))
+            .map(|(id, package)| PackageStatus {
+                id: id.clone(),
+                name: package.folder.clone(),
+                description: None,
+                installed: Some(package.version.clone()),
+                remote: None,
+                comparison: None,
+                size: None,
+            }),
+    );
+    statuses
+}
+
+/// Puts the folder of every installed package back at its place in `modlist.txt` content,
+/// which lists mods from the highest priority down. A package the player disabled stays
+/// disabled, new ones are enabled.
+pub fn arrange_modlist(content: &str, installed: &BTreeMap<String, InstalledPackage>) -> String {
+    if installed.is_empty() {
+        return content.to_string();
+    }
+    let entries: Vec<String> = installed
+        .values()
+        .map(|package| {
+            let prefix = content
+                .lines()
+                .find(|line| is_mod_line(line, &package.folder))
+                .map_or('+', |line| if line.starts_with('-') { '-' } else { '+' });
+            format!("{}{}", prefix, package.folder)
+        })
+        .collect();
+    let mut lines: Vec<String> = content
+        .lines()
+        .filter(|line| {
+            !installed
+                .values()
+                .any(|package| is_mod_line(line, &package.folder))
+        })
+        .map(str::to_string)
+        .collect();
+    for (package, entry) in installed.values().zip(entries) {
+        let position = package
+            .above
+            .as_ref()
+            .and_then(|above| lines.iter().position(|line| is_mod_line(line, above)))
+            .unwrap_or(0);
+        lines.insert(position, entry);
+    }
+    join_lines(&lines, content)
+}
+
+/// Drops `folder` from `modlist.txt` content.
+pub fn remove_from_modlist(content: &str, folder: &str) -> String {
+    let lines: Vec<&str> = content
+        .lines()
+        .filter(|line| !is_mod_line(line, folder))
+        .collect();
+    join_lines(&lines, content)
+}
+
+/// Joins `lines` with the line endings of `original`, ending with one unless it did not.
+fn join_lines(lines: &[impl AsRef<str>], original: &str) -> String {
+    let line_ending = match original.find('\n') {
+        Some(end) if original[..end].ends_with('\r') => "\r\n",
+        _ => "\n",
+    };
+    let mut content = lines
+        .iter()
+        .map(AsRef::as_ref)
+        .collect::<Vec<_>>()
+        .join(line_ending);
+    if !lines.is_empty() && (original.is_empty() || original.ends_with('\n')) {
+        content.push_str(line_ending);
+    }
+    content
+}
+
+/// An enabled (`+`) or disabled (`-`) entry for `folder` in `modlist.txt`.
+fn is_mod_line(line: &str, folder: &str) -> bool {
+    line.strip_prefix(['+', '-'])
+        .is_some_and(|name| name.trim_end() == folder)
+}
+
+/// Adds the plugins of every installed package that `plugins` does not list yet at the end.
+pub fn add_plugins(
+    plugins: &mut PluginList,
+    installed: &BTreeMap<String, InstalledPackage>,
+    active: bool,
+) {
+    for plugin in installed.values().flat_map(|package| &package.plugins) {
+        plugins.push(plugin, active);
+    }
+}
+
+/// Removes `plugins` from the plugin list at `path`, enabled or not.
+pub fn remove_plugins(path: &Path, plugins: &[String]) -> io::Result<()> {
+    if !path.exists() {
+        return Ok(());
+    }
+    let mut list = PluginList::read(path)?;
+    list.remove(plugins.iter().map(String::as_str));
+    list.write(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn installed(packages: &[(&str, &str, Option<&str>)]) -> BTreeMap<String, InstalledPackage> {
+        packages
+            .iter()
+            .map(|(id, folder, above)| {
+                (
+                    id.to_string(),
+                    InstalledPackage {
+                        folder: folder.to_string(),
+                        version: PatchVersion::parse("1.0").unwrap(),
+                        above: above.map(str::to_string),
+                        plugins: Vec::new(),
+                    },
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn arrange_modlist_places_packages_above_their_mod() {
+        let content = "+RFAD_PATCH\n+Unofficial Patch\n-Disabled Mod\n";
+        let packages = installed(&[("hotfix", "RFAD_HOTFIX", Some("RFAD_PATCH"))]);
+        assert_eq!(
+            arrange_modlist(content, &packages),
+            "+RFAD_HOTFIX\n+RFAD_PATCH\n+Unofficial Patch\n-Disabled Mod\n"
+        );
+    }
+
+    #[test]
+    fn arrange_modlist_moves_packages_already_listed() {
+        let content = "+RFAD_PATCH\n-RFAD_HOTFIX\n+Unofficial Patch\n+RFAD_HOTFIX\n";
+        let packages = installed(&[("hotfix", "RFAD_HOTFIX", Some("Unofficial Patch"))]);
+        assert_eq!(
+            arrange_modlist(content, &packages),
+            "+RFAD_PATCH\n-RFAD_HOTFIX\n+Unofficial Patch\n"
+        );
+    }
+
+    #[test]
+    fn arrange_modlist_keeps_line_endings() {
+        let content = "+RFAD_PATCH\r\n+Unofficial Patch\r\n";
+        let packages = installed(&[("hotfix", "RFAD_HOTFIX", Some("Unofficial Patch"))]);
+        assert_eq!(
+            arrange_modlist(content, &packages),
+            "+RFAD_PATCH\r\n+RFAD_HOTFIX\r\n+Unofficial Patch\r\n"
+        );
+        assert_eq!(
+            remove_from_modlist("+RFAD_HOTFIX\r\n+RFAD_PATCH", "RFAD_HOTFIX"),
+            "+RFAD_PATCH"
+        );
+    }
+
+    #[test]
+    fn arrange_modlist_puts_packages_on_top_without_their_mod() {
+        let content = "+RFAD_PATCH\n";
+        let packages = installed(&[
+            ("hotfix", "RFAD_HOTFIX", Some("Missing Mod")),
+            ("music", "RFAD_MUSIC", None),
+        ]);
+        assert_eq!(
+            arrange_modlist(content, &packages),
+            "+RFAD_MUSIC\n+RFAD_HOTFIX\n+RFAD_PATCH\n"
+        );
+    }
+
+    #[test]
+    fn arrange_modlist_matches_whole_folder_names() {
+        let content = "+RFAD_PATCH\n+RFAD_HOTFIX_OLD\n";
+        let packages = installed(&[("hotfix", "RFAD_HOTFIX", Some("RFAD_PATCH"))]);
+        assert_eq!(
+            arrange_modlist(content, &packages),
+            "+RFAD_HOTFIX\n+RFAD_PATCH\n+RFAD_HOTFIX_OLD\n"
+        );
+    }
+
+    #[test]
+    fn arrange_modlist_without_packages_changes_nothing() {
+        let content = "+RFAD_PATCH\r\n+Unofficial Patch";
+        assert_eq!(arrange_modlist(content, &BTreeMap::new()), content);
+    }
+
+    #[test]
+    fn remove_from_modlist_drops_enabled_and_disabled_entries() {
+        assert_eq!(
+            remove_from_modlist("+RFAD_HOTFIX\n+RFAD_PATCH\n-RFAD_HOTFIX\n", "RFAD_HOTFIX"),
+            "+RFAD_PATCH\n"
+        );
+    }
+
+    #[test]
+    fn parse_rejects_folders_outside_mods() {
+        for folder in ["../RFAD", "RFAD/Sub", "rfad_patch", ""] {
+            let content = format!(
+                r#"[{{ "id": "a", "name": "A", "folder": "{}", "version": "1", "archive": "a.zip" }}]"#,
+                folder
+            );
+            assert!(parse(&content).is_err(), "{:?}", folder);
+        }
+    }
+}
@@ -0,0 +1,313 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Encoding {
+    Utf8,
+    Utf8Bom,
+    /// Not valid UTF-8, so saved in a legacy code page. Every byte is kept as the char of
+    /// the same value, which writes it back unchanged.
+    Legacy,
+}
+
+#[derive(Clone, Debug)]
+enum Line {
+    Plugin {
+        name: String,
+        /// The line as read, `*` of an active plugin included, so that untouched lines are
+        /// written back as they were.
+        text: String,
+    },
+    /// A comment or a blank line.
+    Other(String),
+}
+
+impl Line {
+    fn parse(text: &str) -> Self {
+        let trimmed = text.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            return Line::Other(text.to_string());
+        }
+        let name = match trimmed.strip_prefix('*') {
+            Some(name) => name.trim_start(),
+            None => trimmed,
+        };
+        Line::Plugin {
+            name: name.to_string(),
+            text: text.to_string(),
+        }
+    }
+
+    fn plugin(name: &str, active: bool) -> Self {
+        let text = if active {
+            format!("*{}", name)
+        } else {
+            name.to_string()
+        };
+        Line::Plugin {
+            name: name.to_string(),
+            text,
+        }
+    }
+
+    fn is(&self, key: &str) -> bool {
+        matches!(self, Line::Plugin { name, .. } if name.to_lowercase() == key)
+    }
+}
+
+/// `plugins.txt` or `loadorder.txt`: plugins in load order, `*` marking the active ones in
+/// `plugins.txt`, along with `#` comments. Plugins are told apart ignoring case, as the game
+/// does. Writes back the encoding and line endings it read.
+#[derive(Clone, Debug)]
+pub struct PluginList {
+    lines: Vec<Line>,
+    encoding: Encoding,
+    line_ending: &'static str,
+    /// Whether the last line ends with a line break.
+    final_line_ending: bool,
+}
+
+impl PluginList {
+    pub fn read(path: &Path) -> io::Result<Self> {
+        Ok(Self::parse(&fs::read(path)?))
+    }
+
+    pub fn parse(bytes: &[u8]) -> Self {
+        let (encoding, text) = match std::str::from_utf8(bytes) {
+            Ok(text) => match text.strip_prefix('\u{FEFF}') {
+                Some(text) => (Encoding::Utf8Bom, text.to_string()),
+                None => (Encoding::Utf8, text.to_string()),
+            },
+            Err(_) => (Encoding::Legacy, bytes.iter().map(|&b| b as char).collect()),
+        };
+        let line_ending = match text.find('\n') {
+            Some(end) if text[..end].ends_with('\r') => "\r\n",
+            _ => "\n",
+        };
+        PluginList {
+            lines: text.lines().map(Line::parse).collect(),
+            encoding,
+            line_ending,
+            final_line_ending: text.is_empty() || text.ends_with('\n'),
+        }
+    }
+
+    /// The plugins in load order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.lines.iter().filter_map(|line| match line {
+            Line::Plugin { name, .. } => Some(name.as_str()),
+            Line::Other(_) => None,
+        })
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        let key = name.to_lowercase();
+        self.lines.iter().any(|line| line.is(&key))
+    }
+
+    /// Drops every line of `names`.
+    pub fn remove<'a>(&mut self, names: impl IntoIterator<Item = &'a str>) {
+        let keys: HashSet<String> = names.into_iter().map(str::to_lowercase).collect();
+        self.lines.retain(|line| match line {
+            Line::Plugin { name, .. } => !keys.contains(&name.to_lowercase()),
+            Line::Other(_) => true,
+        });
+    }
+
+    /// Moves `names` right above `anchor` in the given order, active or not. Changes
+    /// nothing and returns `false` when `anchor` is not listed.
+    pub fn place_before(&mut self, names: &[String], anchor: &str, active: bool) -> bool {
+        let anchor_key = anchor.to_lowercase();
+        if !self.lines.iter().any(|line| line.is(&anchor_key)) {
+            return false;
+        }
+        let mut seen = HashSet::new();
+        let names: Vec<&str> = names
+            .iter()
+            .map(String::as_str)
+            .filter(|name| {
+                let key = name.to_lowercase();
+                key != anchor_key && seen.insert(key)
+            })
+            .collect();
+        self.remove(names.iter().copied());
+
+        let position = self
+            .lines
+            .iter()
+            .position(|line| line.is(&anchor_key))
+            .unwrap_or_default();
+        self.lines.splice(
+            position..position,
+            names.iter().map(|name| Line::plugin(name, active)),
+        );
+        true
+    }
+
+    /// Adds `name` at the end unless it is listed already.
+    pub fn push(&mut self, name: &str, active: bool) {
+        if !self.contains(name) {
+            self.lines.push(Line::plugin(name, active));
+        }
+    }
+
+    /// The file content, failing when a plugin name cannot be written in a legacy encoding.
+    pub fn to_bytes(&self) -> io::Result<Vec<u8>> {
+        let mut text = self
+            .lines
+            .iter()
+            .map(|line| match line {
+                Line::Plugin { text, .. } | Line::Other(text) => text.as_str(),
+            })
+            .collect::<Vec<_>>()
+            .join(self.line_ending);
+        if self.final_line_ending && !self.lines.is_empty() {
+            text.push_str(self.line_ending);
+        }
+
+        match self.encoding {
+            Encoding::Utf8 => Ok(text.into_bytes()),
+            Encoding::Utf8Bom => Ok(["\u{FEFF}", &text].concat().into_bytes()),
+            Encoding::Legacy => text
+                .chars()
+                .map(|c| {
+                    u8::try_from(c).map_err(|_| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("{:?} does not fit the plugin list's encoding", c),
+                        )
+                    })
+                })
+                .collect(),
+        }
+    }
+
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.to_bytes()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(list: &PluginList) -> Vec<&str> {
+        list.names().collect()
+    }
+
+    fn text(list: &PluginList) -> String {
+        String::from_utf8(list.to_bytes().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn tells_plugins_apart_by_whole_name() {
+        let mut list = PluginList::parse(b"*MyFoo.esp\n*Foo.esp\n");
+        assert!(list.contains("Foo.esp"));
+        list.remove(["Foo.esp"]);
+        assert_eq!(names(&list), ["MyFoo.esp"]);
+        assert!(!list.contains("Foo.esp"));
+    }
+
+    #[test]
+    fn ignores_case() {
+        let mut list = PluginList::parse(b"*Skyrim.esm\n*RFAD_Patch.esp\n");
+        assert!(list.contains("rfad_patch.ESP"));
+        list.push("SKYRIM.ESM", true);
+        assert_eq!(names(&list), ["Skyrim.esm", "RFAD_Patch.esp"]);
+        list.remove(["rfad_patch.esp"]);
+        assert_eq!(names(&list), ["Skyrim.esm"]);
+    }
+
+    #[test]
+    fn keeps_comments_and_active_markers() {
+        let content = "# This file is used by Skyrim to keep track of your downloaded content.\n\
+                       *Skyrim.esm\n\
+                       Inactive.esp\n\
+                       \n\
+                       *Requiem for the Indifferent.esp\n";
+        let mut list = PluginList::parse(content.as_bytes());
+        assert_eq!(
+            names(&list),
+            [
+                "Skyrim.esm",
+                "Inactive.esp",
+                "Requiem for the Indifferent.esp"
+            ]
+        );
+        assert_eq!(text(&list), content);
+
+        assert!(list.place_before(
+            &["Patch.esp".to_string()],
+            "requiem for the indifferent.esp",
+            true
+        ));
+        assert_eq!(
+            text(&list),
+            "# This file is used by Skyrim to keep track of your downloaded content.\n\
+             *Skyrim.esm\n\
+             Inactive.esp\n\
+             \n\
+             *Patch.esp\n\
+             *Requiem for the Indifferent.esp\n"
+        );
+    }
+
+    #[test]
+    fn place_before_moves_listed_plugins() {
+        let mut list = PluginList::parse(b"Patch.esp\nA.esp\nAnchor.esp\n");
+        assert!(list.place_before(
+            &["patch.esp".to_string(), "B.esp".to_string()],
+            "Anchor.esp",
+            false
+        ));
+        assert_eq!(names(&list), ["A.esp", "patch.esp", "B.esp", "Anchor.esp"]);
+    }
+
+    #[test]
+    fn place_before_needs_the_anchor() {
+        let mut list = PluginList::parse(b"*Skyrim.esm\n*A.esp\n");
+        assert!(!list.place_before(&["A.esp".to_string()], "Anchor.esp", true));
+        assert_eq!(text(&list), "*Skyrim.esm\n*A.esp\n");
+    }
+
+    #[test]
+    fn keeps_line_endings() {
+        let mut list = PluginList::parse(b"*Skyrim.esm\r\n*A.esp\r\n");
+        list.push("B.esp", true);
+        assert_eq!(text(&list), "*Skyrim.esm\r\n*A.esp\r\n*B.esp\r\n");
+
+        let mut list = PluginList::parse(b"*Skyrim.esm\n*A.esp");
+        list.push("B.esp", false);
+        assert_eq!(text(&list), "*Skyrim.esm\n*A.esp\nB.esp");
+    }
+
+    #[test]
+    fn keeps_a_byte_order_mark() {
+        let content = "\u{FEFF}*Skyrim.esm\n*Зелья.esp\n";
+        let list = PluginList::parse(content.as_bytes());
+        assert_eq!(names(&list), ["Skyrim.esm", "Зелья.esp"]);
+        assert_eq!(list.to_bytes().unwrap(), content.as_bytes());
+
+        let list = PluginList::parse(b"*Skyrim.esm\n");
+        assert!(!list.to_bytes().unwrap().starts_with("\u{FEFF}".as_bytes()));
+    }
+
+    #[test]
+    fn keeps_a_legacy_encoding() {
+        // "*Зелья.esp" in Windows-1251.
+        let content = b"*Skyrim.esm\r\n*\xc7\xe5\xeb\xfc\xff.esp\r\n";
+        let mut list = PluginList::parse(content);
+        assert_eq!(list.to_bytes().unwrap(), content);
+
+        list.push("Patch.esp", true);
+        assert_eq!(
+            list.to_bytes().unwrap(),
+            b"*Skyrim.esm\r\n*\xc7\xe5\xeb\xfc\xff.esp\r\n*Patch.esp\r\n"
+        );
+
+        list.push("Зелья2.esp", true);
+        assert!(list.to_bytes().is_err());
+    }
+}
@@ -0,0 +1,233 @@
+use crate::extract::ArchiveEntry;
+use crate::gdrive::GdriveError;
+use crate::write_log;
+use serde::Serialize;
+use std::fmt;
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+
+/// Created and removed in `mods/` to check that the launcher may write there.
+const WRITE_TEST_FILE_NAME: &str = ".rfad-write-test";
+
+/// Something that would make an install fail halfway. Serialized for the frontend as
+/// `{ "kind": "notEnoughSpace", "required": 1024, "available": 512 }`.
+#[derive(Clone, Debug, Serialize)]
+#[serde(
+    tag = "kind",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum Problem {
+    /// An archive entry that would land outside the patch folder.
+    UnsafePath {
+        entry: String,
+    },
+    NotEnoughSpace {
+        required: u64,
+        available: u64,
+    },
+    NotWritable {
+        path: String,
+        reason: String,
+    },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::UnsafePath { entry } => write!(f, "Unsafe path in update archive: {}", entry),
+            Problem::NotEnoughSpace {
+                required,
+                available,
+            } => write!(
+                f,
+                "Not enough disk space: {} bytes required, {} available",
+                required, available
+            ),
+            Problem::NotWritable { path, reason } => {
+                write!(f, "Cannot write to {}: {}", path, reason)
+            }
+        }
+    }
+}
+
+/// Checks an archive's `entries` before anything is removed: every entry must stay inside
+/// the patch folder, and `mods_dir` must be writable with room for the unpacked files.
+pub fn check_archive(entries: &[ArchiveEntry], mods_dir: &Path) -> Result<(), GdriveError> {
+    let mut problems: Vec<_> = entries
+        .iter()
+        .filter(|entry| entry.path.is_none())
+        .map(|entry| Problem::UnsafePath {
+            entry: entry.name.clone(),
+        })
+        .collect();
+    let required = entries.iter().map(|entry| entry.size).sum();
+    problems.extend(check_target(mods_dir, required));
+    finish(problems)
+}
+
+/// Checks that `mods_dir` is writable and has `required` bytes free.
+pub fn check_space(mods_dir: &Path, required: u64) -> Result<(), GdriveError> {
+    finish(check_target(mods_dir, required))
+}
+
+fn check_target(mods_dir: &Path, required: u64) -> Vec<Problem> {
+    let mut problems = Vec::new();
+
+    let probe = mods_dir.join(WRITE_TEST_FILE_NAME);
+    match fs::create_dir_all(mods_dir).and_then(|_| File::create(&probe)) {
+        Ok(_) => {
+            let _ = fs::remove_file(&probe);
+        }
+        Err(e) => problems.push(Problem::NotWritable {
+            path: mods_dir.display().to_string(),
+            reason: e.to_string(),
+        }),
+    }
+
+    match available_space(mods_dir) {
+        Ok(available) if available < required => problems.push(Problem::NotEnoughSpace {
+            required,
+            available,
+        }),
+        Ok(_) => {}
+        Err(e) => write_log(&format!(
+            "Failed to check free space on {}: {}",
+            mods_dir.display(),
+            e
+        )),
+    }
+
+    problems
+}
+
+fn finish(problems: Vec<Problem>) -> Result<(), GdriveError> {
+    if problems.is_empty() {
+        return Ok(());
+    }
+    for problem in &problems {
+        write_log(&format!("Preflight: {}", problem));
+    }
+    Err(GdriveError::Preflight(problems))
+}
+
+#[cfg(unix)]
+fn available_space(path: &Path) -> io::Result<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: `path` is NUL-terminated and `stat` is only read after a successful call.
+    if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let stat = unsafe { stat.assume_init() };
+    // The field widths differ between platforms.
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(windows)]
+fn available_space(path: &Path) -> io::Result<u64> {
+    use std::os::windows::ffi::OsStrExt;
+    use windows_sys::Win32::Storage::FileSystem::GetDiskFreeSpaceExW;
+
+    let path: Vec<u16> = path.as_os_str().encode_wide().chain([0]).collect();
+    let mut available = 0;
+    // SAFETY: `path` is NUL-terminated and the totals we do not need may be null.
+    let ok = unsafe {
+        GetDiskFreeSpaceExW(
+            path.as_ptr(),
+            &mut available,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+        )
+    };
+    if ok == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(available)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn mods_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("preflight-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn entry(name: &str, size: u64) -> ArchiveEntry {
+        ArchiveEntry {
+            name: name.to_string(),
+            path: (!name.contains("..")).then(|| PathBuf::from(name)),
+            size,
+            is_dir: false,
+            crc32: None,
+        }
+    }
+
+    fn problems(result: Result<(), GdriveError>) -> Vec<Problem> {
+        match result {
+            Err(GdriveError::Preflight(problems)) => problems,
+            other => panic!("expected preflight problems, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn passes_a_safe_archive_and_creates_the_folder() {
+        let dir = mods_dir("safe");
+        let entries = [entry("RFAD_PATCH.esp", 10), entry("meshes/a.nif", 20)];
+        check_archive(&entries, &dir).unwrap();
+        assert!(dir.is_dir());
+        assert!(!dir.join(WRITE_TEST_FILE_NAME).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reports_unsafe_paths() {
+        let dir = mods_dir("unsafe");
+        let entries = [entry("RFAD_PATCH.esp", 10), entry("../../evil.dll", 10)];
+        let problems = problems(check_archive(&entries, &dir));
+        assert!(matches!(
+            problems.as_slice(),
+            [Problem::UnsafePath { entry }] if entry == "../../evil.dll"
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reports_a_folder_that_cannot_be_created() {
+        let dir = mods_dir("missing");
+        fs::create_dir_all(&dir).unwrap();
+        // A file where the mods folder should be.
+        let blocked = dir.join("mods");
+        fs::write(&blocked, "").unwrap();
+        let problems = problems(check_archive(&[entry("RFAD_PATCH.esp", 10)], &blocked));
+        assert!(problems
+            .iter()
+            .any(|problem| matches!(problem, Problem::NotWritable { .. })));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn requires_the_unpacked_size_of_every_entry() {
+        let dir = mods_dir("space");
+        let entries = [
+            entry("a.bsa", 1 << 61),
+            entry("b.bsa", 1 << 61),
+            entry("c.esp", 1),
+        ];
+        let problems = problems(check_archive(&entries, &dir));
+        assert!(matches!(
+            problems.as_slice(),
+            [Problem::NotEnoughSpace { required, .. }] if *required == (1 << 62) + 1
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
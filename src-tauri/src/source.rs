@@ -0,0 +1,490 @@
+use crate::config::{self, ConfigState, SourceConfig};
+use crate::gdrive::{self, DriveEntry, GdriveError, GoogleDriveClient};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures::{Stream, TryStreamExt};
+use google_drive3::yup_oauth2;
+use serde::Deserialize;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use tauri::utils::mime_type::MimeType;
+use tauri::{AppHandle, Manager};
+use tokio::sync::OnceCell;
+use tokio_util::sync::CancellationToken;
+
+pub const ZIP_MIME_TYPE: &str = "application/x-zip-compressed";
+pub const GOOGLE_DOC_MIME_TYPE: &str = "application/vnd.google-apps.document";
+const HTTP_INDEX_FILE_NAME: &str = "index.json";
+
+/// A file's content as it arrives.
+pub type ByteStream = Pin<Box<dyn Stream<Item = Result<bytes::Bytes, GdriveError>> + Send>>;
+
+/// A file published by an [`UpdateSource`]. `id` is whatever the source needs to fetch it
+/// again: a Drive file id, a path relative to the mirror root or a local file name.
+#[derive(Clone, Debug)]
+pub struct RemoteFile {
+    pub id: String,
+    pub name: String,
+    pub mime_type: String,
+    pub size: Option<u64>,
+    pub modified_time: Option<DateTime<Utc>>,
+    /// Hex MD5 of the content, when the source reports one.
+    pub md5: Option<String>,
+}
+
+/// Where patches, the remote version and the modlist are published.
+#[async_trait]
+pub trait UpdateSource: Send + Sync {
+    async fn list_files(&self) -> Result<Vec<RemoteFile>, GdriveError>;
+
+    /// Lists every file under the subfolder `dir`, with `name` set to the `/`-separated path
+    /// relative to it. Empty when there is no such folder.
+    async fn list_tree(&self, dir: &str) -> Result<Vec<RemoteFile>, GdriveError>;
+
+    /// Saves `file` to `output_path`, stopping once `cancel` is cancelled.
+    async fn download_file(
+        &self,
+        file: &RemoteFile,
+        output_path: &Path,
+        app: AppHandle,
+        cancel: &CancellationToken,
+    ) -> Result<(), GdriveError>;
+
+    async fn load_text(&self, file: &RemoteFile) -> Result<String, GdriveError>;
+
+    /// Opens a binary file for reading from the start, without saving it anywhere.
+    async fn open_stream(&self, file: &RemoteFile) -> Result<ByteStream, GdriveError>;
+
+    /// Reads `range` of a binary file, e.g. to look at an archive's index without
+    /// downloading the archive.
+    async fn read_range(
+        &self,
+        file: &RemoteFile,
+        range: Range<u64>,
+    ) -> Result<bytes::Bytes, GdriveError>;
+}
+
+/// The body of a response to a Range request. Servers that answer with the whole file are
+/// refused rather than read to the end.
+async fn ranged_body(response: reqwest::Response) -> Result<bytes::Bytes, GdriveError> {
+    if response.status() != reqwest::StatusCode::PARTIAL_CONTENT {
+        return Err(GdriveError::Network(format!(
+            "{} does not serve byte ranges",
+            response.url()
+        )));
+    }
+    Ok(response.bytes().await?)
+}
+
+/// Finds a file by its exact name, falling back to the name without extension, so that
+/// a Drive document called `version` and a `version.txt` on a mirror are found the same way.
+pub fn find_file<'a>(files: &'a [RemoteFile], name: &str) -> Option<&'a RemoteFile> {
+    files.iter().find(|f| f.name == name).or_else(|| {
+        files.iter().find(|f| {
+            Path::new(&f.name)
+                .file_stem()
+                .is_some_and(|stem| stem == name)
+        })
+    })
+}
+
+fn guess_mime_type(name: &str) -> String {
+    let extension = Path::new(name)
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    match extension.as_str() {
+        "zip" => ZIP_MIME_TYPE,
+        "7z" => "application/x-7z-compressed",
+        "zst" | "tzst" => "application/zstd",
+        "json" => "application/json",
+        "txt" => "text/plain",
+        _ => "application/octet-stream",
+    }
+    .to_string()
+}
+
+/// Managed state holding the source once it has been built, so every command shares one
+/// authenticated client and its connection pool.
+#[derive(Default)]
+pub struct SourceState(OnceCell<Arc<dyn UpdateSource>>);
+
+/// Returns the shared source, building it on first use. Concurrent callers wait for the
+/// same initialization; a failed one is retried by the next call.
+pub async fn connect(app: &AppHandle) -> Result<Arc<dyn UpdateSource>, GdriveError> {
+    let state = app.state::<SourceState>();
+    state.0.get_or_try_init(|| build(app)).await.cloned()
+}
+
+/// Builds the source configured in `launcher.toml`.
+async fn build(app: &AppHandle) -> Result<Arc<dyn UpdateSource>, GdriveError> {
+    let state = app.state::<ConfigState>();
+    let config = state.get().map_err(GdriveError::Config)?;
+    let connections = config.download.connections;
+
+    Ok(match &config.source {
+        SourceConfig::GoogleDrive {
+            folder_id,
+            credentials,
+            api_url,
+        } => {
+            let key = yup_oauth2::read_service_account_key(config::resolve_path(credentials))
+                .await
+                .map_err(|e| {
+                    GdriveError::Config(format!("Failed to read Drive credentials: {}", e))
+                })?;
+            Arc::new(GoogleDriveSource {
+                client: GoogleDriveClient::new(key, api_url.as_deref(), connections).await?,
+                folder_id: folder_id.clone(),
+            })
+        }
+        SourceConfig::Http { base_url } => Arc::new(HttpSource::new(base_url, connections)),
+        SourceConfig::Local { path } => Arc::new(LocalSource::new(config::resolve_path(path))),
+    })
+}
+
+pub struct GoogleDriveSource {
+    client: GoogleDriveClient,
+    folder_id: String,
+}
+
+fn from_entry(entry: DriveEntry) -> RemoteFile {
+    RemoteFile {
+        id: entry.id,
+        name: entry.path,
+        mime_type: entry.mime_type,
+        size: entry.size,
+        modified_time: entry.modified_time,
+        md5: entry.md5,
+    }
+}
+
+#[async_trait]
+impl UpdateSource for GoogleDriveSource {
+    async fn list_files(&self) -> Result<Vec<RemoteFile>, GdriveError> {
+        Ok(self
+            .client
+            .list_files(&self.folder_id, false)
+            .await?
+            .into_iter()
+            .filter(|entry| !entry.is_folder())
+            .map(from_entry)
+            .collect())
+    }
+
+    async fn list_tree(&self, dir: &str) -> Result<Vec<RemoteFile>, GdriveError> {
+        let root = self.client.list_files(&self.folder_id, false).await?;
+        let Some(folder) = root
+            .into_iter()
+            .find(|entry| entry.is_folder() && entry.name == dir)
+        else {
+            return Ok(Vec::new());
+        };
+
+        Ok(self
+            .client
+            .list_files(&folder.id, true)
+            .await?
+            .into_iter()
+            .filter(|entry| !entry.is_folder())
+            .map(from_entry)
+            .collect())
+    }
+
+    async fn download_file(
+        &self,
+        file: &RemoteFile,
+        output_path: &Path,
+        app: AppHandle,
+        cancel: &CancellationToken,
+    ) -> Result<(), GdriveError> {
+        let mime = if gdrive::is_google_doc(&file.mime_type) {
+            MimeType::Txt
+        } else {
+            MimeType::OctetStream
+        };
+        self.client
+            .download_file(&file.id, mime, file.size, output_path, app, cancel)
+            .await
+    }
+
+    async fn load_text(&self, file: &RemoteFile) -> Result<String, GdriveError> {
+        self.client.load_text(&file.id, &file.mime_type).await
+    }
+
+    async fn open_stream(&self, file: &RemoteFile) -> Result<ByteStream, GdriveError> {
+        let response = self.client.open_media(&file.id, None).await?;
+        Ok(Box::pin(response.bytes_stream().map_err(GdriveError::from)))
+    }
+
+    async fn read_range(
+        &self,
+        file: &RemoteFile,
+        range: Range<u64>,
+    ) -> Result<bytes::Bytes, GdriveError> {
+        ranged_body(self.client.open_media(&file.id, Some(range)).await?).await
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct HttpIndexEntry {
+    name: String,
+    mime_type: Option<String>,
+    size: Option<u64>,
+    md5: Option<String>,
+}
+
+/// A plain HTTPS mirror. The mirror root must serve an `index.json` listing its files,
+/// e.g. `[{ "name": "update.zip", "md5": "..." }, { "name": "version.txt" }]`, and so must
+/// every folder read with `list_tree`, with names relative to that folder.
+pub struct HttpSource {
+    client: reqwest::Client,
+    base_url: String,
+    connections: usize,
+}
+
+impl HttpSource {
+    pub fn new(base_url: &str, connections: usize) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            connections,
+        }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}/{}", self.base_url, path)
+    }
+
+    async fn get(&self, path: &str) -> Result<reqwest::Response, GdriveError> {
+        let url = self.url(path);
+        let response = self.client.get(&url).send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(GdriveError::from_status(
+                status.as_u16(),
+                format!("{} returned {}", url, status),
+            ));
+        }
+        Ok(response)
+    }
+
+    /// Reads `<prefix>index.json`. Entry ids keep the prefix so they can be fetched directly.
+    async fn read_index(&self, prefix: &str) -> Result<Vec<RemoteFile>, GdriveError> {
+        let entries = self
+            .get(&format!("{}{}", prefix, HTTP_INDEX_FILE_NAME))
+            .await?
+            .json::<Vec<HttpIndexEntry>>()
+            .await?;
+
+        Ok(entries
+            .into_iter()
+            .map(|entry| RemoteFile {
+                id: format!("{}{}", prefix, entry.name),
+                mime_type: entry
+                    .mime_type
+                    .unwrap_or_else(|| guess_mime_type(&entry.name)),
+                name: entry.name,
+                size: entry.size,
+                modified_time: None,
+                md5: entry.md5,
+            })
+            .collect())
+    }
+}
+
+#[async_trait]
+impl UpdateSource for HttpSource {
+    async fn list_files(&self) -> Result<Vec<RemoteFile>, GdriveError> {
+        self.read_index("").await
+    }
+
+    async fn list_tree(&self, dir: &str) -> Result<Vec<RemoteFile>, GdriveError> {
+        match self.read_index(&format!("{}/", dir)).await {
+            Err(GdriveError::NotFound(_)) => Ok(Vec::new()),
+            result => result,
+        }
+    }
+
+    async fn download_file(
+        &self,
+        file: &RemoteFile,
+        output_path: &Path,
+        app: AppHandle,
+        cancel: &CancellationToken,
+    ) -> Result<(), GdriveError> {
+        let url = self.url(&file.id);
+        crate::download::fetch(
+            || self.client.get(&url),
+            &file.id,
+            file.size,
+            self.connections,
+            output_path,
+            &app,
+            cancel,
+        )
+        .await
+    }
+
+    async fn load_text(&self, file: &RemoteFile) -> Result<String, GdriveError> {
+        Ok(self.get(&file.id).await?.text().await?)
+    }
+
+    async fn open_stream(&self, file: &RemoteFile) -> Result<ByteStream, GdriveError> {
+        let response = self.get(&file.id).await?;
+        Ok(Box::pin(response.bytes_stream().map_err(GdriveError::from)))
+    }
+
+    async fn read_range(
+        &self,
+        file: &RemoteFile,
+        range: Range<u64>,
+    ) -> Result<bytes::Bytes, GdriveError> {
+        let url = self.url(&file.id);
+        let response = self
+            .client
+            .get(&url)
+            .header(
+                reqwest::header::RANGE,
+                format!("bytes={}-{}", range.start, range.end - 1),
+            )
+            .send()
+            .await?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(GdriveError::from_status(
+                status.as_u16(),
+                format!("{} returned {}", url, status),
+            ));
+        }
+        ranged_body(response).await
+    }
+}
+
+/// A folder on disk laid out like the Drive folder. Handy for testing the update flow
+/// without touching the network.
+pub struct LocalSource {
+    root: PathBuf,
+}
+
+impl LocalSource {
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+}
+
+#[async_trait]
+impl UpdateSource for LocalSource {
+    async fn list_files(&self) -> Result<Vec<RemoteFile>, GdriveError> {
+        let entries = std::fs::read_dir(&self.root).map_err(|e| {
+            GdriveError::NotFound(format!(
+                "Error reading update folder {}: {}",
+                self.root.display(),
+                e
+            ))
+        })?;
+
+        Ok(entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let metadata = entry.metadata().ok().filter(|m| m.is_file())?;
+                let name = entry.file_name().to_string_lossy().to_string();
+                Some(RemoteFile {
+                    id: name.clone(),
+                    mime_type: guess_mime_type(&name),
+                    name,
+                    size: Some(metadata.len()),
+                    modified_time: metadata.modified().ok().map(DateTime::from),
+                    md5: None,
+                })
+            })
+            .collect())
+    }
+
+    async fn list_tree(&self, dir: &str) -> Result<Vec<RemoteFile>, GdriveError> {
+        let mut files = Vec::new();
+        let mut folders = vec![PathBuf::from(dir)];
+
+        while let Some(folder) = folders.pop() {
+            let Ok(entries) = std::fs::read_dir(self.root.join(&folder)) else {
+                continue;
+            };
+            for entry in entries {
+                let entry = entry?;
+                let relative = folder.join(entry.file_name());
+                let metadata = entry.metadata()?;
+                if metadata.is_dir() {
+                    folders.push(relative);
+                    continue;
+                }
+                let name = relative
+                    .strip_prefix(dir)
+                    .unwrap_or(&relative)
+                    .to_string_lossy()
+                    .replace('\\', "/");
+                files.push(RemoteFile {
+                    id: relative.to_string_lossy().to_string(),
+                    mime_type: guess_mime_type(&name),
+                    name,
+                    size: Some(metadata.len()),
+                    modified_time: metadata.modified().ok().map(DateTime::from),
+                    md5: None,
+                });
+            }
+        }
+
+        Ok(files)
+    }
+
+    async fn download_file(
+        &self,
+        file: &RemoteFile,
+        output_path: &Path,
+        app: AppHandle,
+        cancel: &CancellationToken,
+    ) -> Result<(), GdriveError> {
+        let source = tokio::fs::File::open(self.root.join(&file.id)).await?;
+        let total_size = source.metadata().await?.len();
+
+        crate::download::save_stream(
+            source,
+            GdriveError::from,
+            total_size,
+            output_path,
+            &app,
+            cancel,
+        )
+        .await
+    }
+
+    async fn load_text(&self, file: &RemoteFile) -> Result<String, GdriveError> {
+        Ok(tokio::fs::read_to_string(self.root.join(&file.id)).await?)
+    }
+
+    async fn open_stream(&self, file: &RemoteFile) -> Result<ByteStream, GdriveError> {
+        let source = tokio::fs::File::open(self.root.join(&file.id)).await?;
+        Ok(Box::pin(
+            tokio_util::io::ReaderStream::new(source).map_err(GdriveError::from),
+        ))
+    }
+
+    async fn read_range(
+        &self,
+        file: &RemoteFile,
+        range: Range<u64>,
+    ) -> Result<bytes::Bytes, GdriveError> {
+        use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+        let mut source = tokio::fs::File::open(self.root.join(&file.id)).await?;
+        source.seek(std::io::SeekFrom::Start(range.start)).await?;
+        let mut buffer = Vec::new();
+        source
+            .take(range.end - range.start)
+            .read_to_end(&mut buffer)
+            .await?;
+        Ok(buffer.into())
+    }
+}
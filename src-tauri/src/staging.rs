@@ -0,0 +1,129 @@
+use crate::gdrive::GdriveError;
+use crate::versions::PatchVersion;
+use crate::{base_dir, write_log};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+use tauri::{AppHandle, Manager};
+use tokio::sync::{Mutex, MutexGuard};
+
+/// Holds a patch archive downloaded in the background until it is applied.
+const STAGING_DIR_NAME: &str = "update_staging";
+const STAGED_FILE_NAME: &str = "staged.json";
+const MO2_PROCESS_NAME: &str = "ModOrganizer.exe";
+
+/// A verified archive waiting in the staging folder, with everything needed to install it
+/// without going back to the update source.
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StagedUpdate {
+    pub version: PatchVersion,
+    pub file_name: String,
+    pub load_order: Option<String>,
+}
+
+/// Managed state that keeps a background download and an install from using the staging
+/// folder at the same time.
+#[derive(Default)]
+pub struct StagingLock(Mutex<()>);
+
+/// Waits until nothing else uses the staging folder and keeps it until the guard is dropped.
+/// An install that waited for a background download finds its archive staged.
+pub async fn lock(app: &AppHandle) -> MutexGuard<'_, ()> {
+    let lock = &app.state::<StagingLock>().inner().0;
+    match lock.try_lock() {
+        Ok(guard) => guard,
+        Err(_) => {
+            write_log("Waiting for the background download to finish");
+            lock.lock().await
+        }
+    }
+}
+
+pub fn staging_dir() -> PathBuf {
+    base_dir().join(STAGING_DIR_NAME)
+}
+
+pub fn archive_path(staged: &StagedUpdate) -> PathBuf {
+    staging_dir().join(&staged.file_name)
+}
+
+/// The staged update, if its archive is still there.
+pub fn read() -> Option<StagedUpdate> {
+    let content = fs::read(staging_dir().join(STAGED_FILE_NAME)).ok()?;
+    let staged: StagedUpdate = serde_json::from_slice(&content).ok()?;
+    archive_path(&staged).is_file().then_some(staged)
+}
+
+/// Records `staged` once its archive has been downloaded and verified.
+pub fn write(staged: &StagedUpdate) -> Result<(), GdriveError> {
+    let content = serde_json::to_vec_pretty(staged).map_err(|e| GdriveError::Io(e.to_string()))?;
+    Ok(fs::write(staging_dir().join(STAGED_FILE_NAME), content)?)
+}
+
+/// Clears the staging folder except for `file_name` and the `.part` files of its
+/// interrupted download, which the next download resumes.
+pub fn clear_except(file_name: &str) {
+    let Ok(entries) = fs::read_dir(staging_dir()) else {
+        return;
+    };
+    let keep = [
+        file_name.to_string(),
+        format!("{}.part", file_name),
+        format!("{}.part.json", file_name),
+    ];
+    for entry in entries.flatten() {
+        if keep.iter().any(|name| entry.file_name() == name.as_str()) {
+            continue;
+        }
+        let path = entry.path();
+        let result = if path.is_dir() {
+            fs::remove_dir_all(&path)
+        } else {
+            fs::remove_file(&path)
+        };
+        if let Err(e) = result {
+            write_log(&format!("Failed to remove {}: {}", path.display(), e));
+        }
+    }
+}
+
+pub fn clear() {
+    let dir = staging_dir();
+    if dir.exists() {
+        if let Err(e) = fs::remove_dir_all(&dir) {
+            write_log(&format!("Failed to remove {}: {}", dir.display(), e));
+        }
+    }
+}
+
+/// Refuses to go on while Mod Organizer runs: it keeps the patch and profile files open and
+/// rewrites the profile when it exits.
+pub fn ensure_mo2_closed() -> Result<(), GdriveError> {
+    if is_mo2_running() {
+        Err(GdriveError::GameRunning(format!(
+            "{} is running, close it before installing the patch",
+            MO2_PROCESS_NAME
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(windows)]
+fn is_mo2_running() -> bool {
+    use std::os::windows::process::CommandExt;
+    const CREATE_NO_WINDOW: u32 = 0x0800_0000;
+
+    std::process::Command::new("tasklist")
+        .args(["/FI", &format!("IMAGENAME eq {}", MO2_PROCESS_NAME), "/NH"])
+        .creation_flags(CREATE_NO_WINDOW)
+        .output()
+        .map(|output| String::from_utf8_lossy(&output.stdout).contains(MO2_PROCESS_NAME))
+        .unwrap_or(false)
+}
+
+#[cfg(not(windows))]
+fn is_mo2_running() -> bool {
+    false
+}
@@ -0,0 +1,259 @@
+use crate::checksum::{Checksum, StreamHasher};
+use crate::download::Progress;
+use crate::events::UnpackProgress;
+use crate::gdrive::GdriveError;
+use crate::source::{RemoteFile, UpdateSource};
+use crate::LOCAL_UPDATE_FILE_NAME;
+use bytes::{Buf, Bytes};
+use futures::TryStreamExt;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::Path;
+use tauri::{AppHandle, Emitter};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+/// Chunks buffered between the download and the extractor; bounds memory use when the disk
+/// is slower than the network.
+const CHANNEL_CAPACITY: usize = 64;
+
+/// Downloads `zip` and unpacks it into `output` as the bytes arrive, without saving the
+/// archive. Needs entry sizes in the local headers, so archives written with data
+/// descriptors fail with [`GdriveError::Corrupted`]. The checksum is only known at the end:
+/// on an error `output` is left half written for the caller to clean up.
+pub async fn download_and_unpack(
+    app: &AppHandle,
+    cancel: &CancellationToken,
+    source: &dyn UpdateSource,
+    zip: &RemoteFile,
+    expected: Option<Checksum>,
+    output: &Path,
+) -> Result<(), GdriveError> {
+    let total_size = zip.size.unwrap_or(0);
+    let progress = Progress::new(
+        Path::new(LOCAL_UPDATE_FILE_NAME),
+        0,
+        total_size,
+        app,
+        cancel,
+    );
+    let (tx, rx) = mpsc::channel::<Bytes>(CHANNEL_CAPACITY);
+
+    let feed = async move {
+        let mut stream = source.open_stream(zip).await?;
+        let mut hasher = expected.map(StreamHasher::new);
+        let mut received = 0;
+        while let Some(chunk) = stream.try_next().await? {
+            if let Some(hasher) = &mut hasher {
+                hasher.update(&chunk);
+            }
+            received = progress.advance(chunk.len() as u64).await?;
+            if tx.send(chunk).await.is_err() {
+                // The extractor stopped and has its own error to report.
+                break;
+            }
+        }
+        Ok::<_, GdriveError>((received, hasher))
+    };
+    let extract = tokio::task::spawn_blocking({
+        let reader = ChannelReader::new(rx);
+        let output = output.to_path_buf();
+        let app = app.clone();
+        move || {
+            extract(reader, &output, total_size, |percentage| {
+                app.emit("unpack:progress", UnpackProgress { percentage })
+                    .ok();
+            })
+        }
+    });
+
+    let (fed, extracted) = tokio::join!(feed, extract);
+    let (received, hasher) = fed?;
+    extracted.map_err(|e| GdriveError::Io(e.to_string()))??;
+
+    if let Some(size) = zip.size.filter(|&size| size != received) {
+        return Err(GdriveError::Corrupted(format!(
+            "Size mismatch for {}: expected {} bytes, got {}",
+            zip.name, size, received
+        )));
+    }
+    match hasher {
+        Some(hasher) => hasher.verify(&zip.name),
+        None => Ok(()),
+    }
+}
+
+/// Unpacks the archive `reader` receives into `output`, reporting the share of `total_size`
+/// read so far after every entry.
+fn extract(
+    mut reader: ChannelReader,
+    output: &Path,
+    total_size: u64,
+    report: impl Fn(f64),
+) -> Result<(), GdriveError> {
+    loop {
+        {
+            let Some(mut file) = zip::read::read_zipfile_from_stream(&mut reader)
+                .map_err(|e| GdriveError::Corrupted(format!("Corrupted update archive: {}", e)))?
+            else {
+                break;
+            };
+            let name = file.enclosed_name().ok_or_else(|| {
+                GdriveError::Corrupted(format!("Unsafe path in update archive: {}", file.name()))
+            })?;
+            let outpath = output.join(name);
+
+            if file.is_dir() {
+                fs::create_dir_all(&outpath)?;
+            } else {
+                if let Some(parent) = outpath.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                let mut outfile = File::create(&outpath)?;
+                io::copy(&mut file, &mut outfile)?;
+            }
+        }
+
+        if total_size > 0 {
+            report((reader.consumed as f64 / total_size as f64 * 100.0).min(100.0));
+        }
+    }
+
+    // Read the central directory too, so that the checksum covers the whole archive.
+    io::copy(&mut reader, &mut io::sink())?;
+    report(100.0);
+    Ok(())
+}
+
+/// Blocking [`Read`] over the chunks sent by the download task; ends when the sender is
+/// dropped.
+struct ChannelReader {
+    rx: mpsc::Receiver<Bytes>,
+    chunk: Bytes,
+    consumed: u64,
+}
+
+impl ChannelReader {
+    fn new(rx: mpsc::Receiver<Bytes>) -> Self {
+        Self {
+            rx,
+            chunk: Bytes::new(),
+            consumed: 0,
+        }
+    }
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.chunk.is_empty() {
+            match self.rx.blocking_recv() {
+                Some(chunk) => self.chunk = chunk,
+                None => return Ok(0),
+            }
+        }
+        let len = buf.len().min(self.chunk.len());
+        buf[..len].copy_from_slice(&self.chunk[..len]);
+        self.chunk.advance(len);
+        self.consumed += len as u64;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Write};
+    use std::sync::Mutex;
+    use zip::write::SimpleFileOptions;
+
+    fn archive(files: &[(&str, &str)]) -> Vec<u8> {
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, content) in files {
+            writer
+                .start_file(*name, SimpleFileOptions::default())
+                .unwrap();
+            writer.write_all(content.as_bytes()).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    fn output_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("streaming-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    /// Sends `bytes` in `chunk_size` chunks from another thread, as the download does.
+    fn feed(bytes: Vec<u8>, chunk_size: usize) -> ChannelReader {
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        std::thread::spawn(move || {
+            for chunk in bytes.chunks(chunk_size) {
+                if tx.blocking_send(Bytes::copy_from_slice(chunk)).is_err() {
+                    break;
+                }
+            }
+        });
+        ChannelReader::new(rx)
+    }
+
+    #[test]
+    fn reads_across_chunks() {
+        let mut reader = feed(b"0123456789".to_vec(), 3);
+        let mut content = String::new();
+        reader.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "0123456789");
+        assert_eq!(reader.consumed, 10);
+        assert_eq!(reader.read(&mut [0; 4]).unwrap(), 0);
+    }
+
+    #[test]
+    fn unpacks_chunked_input() {
+        let bytes = archive(&[("RFAD_PATCH.esp", "plugin"), ("meshes/a.nif", "mesh")]);
+        let total_size = bytes.len() as u64;
+        let output = output_dir("chunked");
+        let reported = Mutex::new(Vec::new());
+
+        extract(feed(bytes, 7), &output, total_size, |percentage| {
+            reported.lock().unwrap().push(percentage)
+        })
+        .unwrap();
+        assert_eq!(
+            fs::read_to_string(output.join("RFAD_PATCH.esp")).unwrap(),
+            "plugin"
+        );
+        assert_eq!(
+            fs::read_to_string(output.join("meshes/a.nif")).unwrap(),
+            "mesh"
+        );
+        let reported = reported.into_inner().unwrap();
+        assert!(reported.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert_eq!(reported.last(), Some(&100.0));
+        fs::remove_dir_all(&output).unwrap();
+    }
+
+    #[test]
+    fn fails_on_an_archive_cut_off_midway() {
+        // What the extractor sees when a cancelled or failed download drops the sender.
+        let bytes = archive(&[("RFAD_PATCH.esp", &"plugin".repeat(100))]);
+        let cut = bytes[..bytes.len() / 2].to_vec();
+        let output = output_dir("cut");
+
+        assert!(extract(feed(cut, 16), &output, 0, |_| {}).is_err());
+        let _ = fs::remove_dir_all(&output);
+    }
+
+    #[test]
+    fn fails_on_corrupted_input() {
+        let output = output_dir("corrupted");
+        let result = extract(feed(b"not a zip archive".to_vec(), 4), &output, 0, |_| {});
+        assert!(matches!(result, Err(GdriveError::Corrupted(_))));
+        assert!(!output.exists());
+    }
+
+    #[test]
+    fn stopping_the_extractor_stops_the_feed() {
+        let (tx, rx) = mpsc::channel(1);
+        drop(ChannelReader::new(rx));
+        assert!(tx.blocking_send(Bytes::from_static(b"chunk")).is_err());
+    }
+}
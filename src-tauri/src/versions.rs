@@ -0,0 +1,302 @@
+use crate::extract::ArchiveFormat;
+use crate::source::{self, RemoteFile};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::cmp::Ordering;
+use std::fmt;
+
+/// Versioned archives are published as `RFAD_PATCH-<version>.zip`, `.7z` or `.tar.zst`.
+const ARCHIVE_PREFIX: &str = "RFAD_PATCH-";
+
+/// A dot-separated patch version such as `6.2.1`. Parsing ignores a BOM, surrounding
+/// whitespace and leading zeros, so `"17.04\n"` and `17.4` are the same version. Segments
+/// are compared numerically, so `6.10` is newer than `6.9`; trailing `.0`s do not count.
+/// Text marks a pre-release: `7.0.beta` comes before `7.0`, which comes before `7.0.1`.
+#[derive(Clone, Debug)]
+pub struct PatchVersion(Vec<Segment>);
+
+/// Text sorts before every number, so that `7.0.beta` is older than `7.0.1`.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Segment {
+    Text(String),
+    Number(u64),
+}
+
+impl PatchVersion {
+    /// `None` for an empty string or one with empty segments, like `6..2`.
+    pub fn parse(s: &str) -> Option<Self> {
+        let s = s.trim_matches(|c: char| c.is_whitespace() || c == '\u{feff}');
+        let s = s.strip_prefix(['v', 'V']).unwrap_or(s);
+        if s.is_empty() {
+            return None;
+        }
+        s.split('.')
+            .map(|segment| {
+                if segment.is_empty() || segment.contains(char::is_whitespace) {
+                    None
+                } else if segment.bytes().all(|b| b.is_ascii_digit()) {
+                    Some(
+                        segment
+                            .parse()
+                            .map(Segment::Number)
+                            .unwrap_or_else(|_| Segment::Text(segment.to_string())),
+                    )
+                } else {
+                    Some(Segment::Text(segment.to_string()))
+                }
+            })
+            .collect::<Option<_>>()
+            .map(PatchVersion)
+    }
+
+    /// The segments that take part in comparisons.
+    fn significant(&self) -> &[Segment] {
+        let end = self
+            .0
+            .iter()
+            .rposition(|segment| *segment != Segment::Number(0))
+            .map_or(0, |i| i + 1);
+        &self.0[..end]
+    }
+}
+
+impl fmt::Display for PatchVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, segment) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(".")?;
+            }
+            match segment {
+                Segment::Number(number) => write!(f, "{}", number)?,
+                Segment::Text(text) => f.write_str(text)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl PartialEq for PatchVersion {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for PatchVersion {}
+
+impl PartialOrd for PatchVersion {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for PatchVersion {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let (a, b) = (self.significant(), other.significant());
+        let common = a.len().min(b.len());
+        // Past the end of the shorter version, the longer one is a later release if a number
+        // follows and a pre-release of it if text does.
+        let later = |rest: &[Segment]| {
+            matches!(
+                rest.iter().find(|segment| **segment != Segment::Number(0)),
+                Some(Segment::Number(_))
+            )
+        };
+        a[..common]
+            .cmp(&b[..common])
+            .then_with(|| match a.len().cmp(&b.len()) {
+                Ordering::Greater if later(&a[common..]) => Ordering::Greater,
+                Ordering::Greater => Ordering::Less,
+                Ordering::Less if later(&b[common..]) => Ordering::Less,
+                Ordering::Less => Ordering::Greater,
+                Ordering::Equal => Ordering::Equal,
+            })
+    }
+}
+
+/// Sent to the frontend and saved in the journal as the normalized string.
+impl Serialize for PatchVersion {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for PatchVersion {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        PatchVersion::parse(&s)
+            .ok_or_else(|| serde::de::Error::custom(format!("invalid patch version {:?}", s)))
+    }
+}
+
+/// How the published version relates to the installed one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Comparison {
+    /// The published version is newer, or nothing is installed yet.
+    Newer,
+    /// The installed version is newer, e.g. after installing a test build.
+    Older,
+    Equal,
+}
+
+impl Comparison {
+    /// How `remote` relates to `installed`, which is `None` when nothing is installed.
+    pub fn of(installed: Option<&PatchVersion>, remote: &PatchVersion) -> Self {
+        match installed.map(|installed| remote.cmp(installed)) {
+            None | Some(Ordering::Greater) => Comparison::Newer,
+            Some(Ordering::Less) => Comparison::Older,
+            Some(Ordering::Equal) => Comparison::Equal,
+        }
+    }
+}
+
+/// Result of `check_for_update`. `cached_at` is set when the source could not be reached
+/// and `remote` is the version seen at that time.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdateCheck {
+    pub installed: Option<PatchVersion>,
+    pub remote: PatchVersion,
+    pub comparison: Comparison,
+    pub cached_at: Option<DateTime<Utc>>,
+}
+
+impl UpdateCheck {
+    pub fn new(
+        installed: Option<PatchVersion>,
+        remote: PatchVersion,
+        cached_at: Option<DateTime<Utc>>,
+    ) -> Self {
+        let comparison = Comparison::of(installed.as_ref(), &remote);
+        UpdateCheck {
+            installed,
+            remote,
+            comparison,
+            cached_at,
+        }
+    }
+}
+
+/// A patch version that can be installed, as shown in the version picker.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AvailableVersion {
+    pub version: PatchVersion,
+    pub file_name: String,
+    pub size: Option<u64>,
+    pub modified_time: Option<DateTime<Utc>>,
+    pub installed: bool,
+    /// Older than the installed version, so installing it is a downgrade.
+    pub older: bool,
+}
+
+/// The version in a versioned archive's name.
+pub fn archive_version(name: &str) -> Option<PatchVersion> {
+    PatchVersion::parse(ArchiveFormat::strip_extension(
+        name.strip_prefix(ARCHIVE_PREFIX)?,
+    )?)
+}
+
+/// Versioned archives in `files`, newest first.
+fn versioned(files: &[RemoteFile]) -> Vec<(PatchVersion, &RemoteFile)> {
+    let mut archives: Vec<_> = files
+        .iter()
+        .filter_map(|file| Some((archive_version(&file.name)?, file)))
+        .collect();
+    archives.sort_by(|(a, _), (b, _)| b.cmp(a));
+    archives
+}
+
+pub fn available(files: &[RemoteFile], installed: Option<&PatchVersion>) -> Vec<AvailableVersion> {
+    versioned(files)
+        .into_iter()
+        .map(|(version, file)| AvailableVersion {
+            installed: installed.is_some_and(|installed| version == *installed),
+            older: installed.is_some_and(|installed| version < *installed),
+            version,
+            file_name: file.name.clone(),
+            size: file.size,
+            modified_time: file.modified_time,
+        })
+        .collect()
+}
+
+/// The newest versioned archive, or the first archive of any name for folders that only
+/// publish the current one.
+pub fn latest_archive(files: &[RemoteFile]) -> Option<&RemoteFile> {
+    versioned(files).first().map(|(_, file)| *file).or_else(|| {
+        files.iter().find(|file| {
+            file.mime_type == source::ZIP_MIME_TYPE
+                || ArchiveFormat::from_name(&file.name).is_some()
+        })
+    })
+}
+
+pub fn find_archive<'a>(files: &'a [RemoteFile], version: &PatchVersion) -> Option<&'a RemoteFile> {
+    versioned(files)
+        .into_iter()
+        .find(|(candidate, _)| candidate == version)
+        .map(|(_, file)| file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(s: &str) -> PatchVersion {
+        PatchVersion::parse(s).unwrap()
+    }
+
+    #[test]
+    fn parses_version_files() {
+        assert_eq!(version("17.04\n"), version("17.4"));
+        assert_eq!(version("\u{feff}17.4\r\n"), version("17.4"));
+        assert_eq!(version("v6.2").to_string(), "6.2");
+        assert_eq!(version("17.04").to_string(), "17.4");
+    }
+
+    #[test]
+    fn rejects_malformed_versions() {
+        for s in ["", " \n", "\u{feff}", "6..2", "6.2.", "6. 2"] {
+            assert!(PatchVersion::parse(s).is_none(), "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn ignores_trailing_zeros() {
+        assert_eq!(version("7.0"), version("7"));
+        assert_eq!(version("7.0.0"), version("7"));
+        assert!(version("7.0.1") > version("7"));
+        assert!(version("7.0.0.1") > version("7.0"));
+    }
+
+    #[test]
+    fn compares_numerically() {
+        assert!(version("6.10") > version("6.9"));
+        assert!(version("10") > version("9.9.9"));
+        assert!(version("6.2.1") < version("6.3"));
+    }
+
+    #[test]
+    fn text_marks_a_pre_release() {
+        assert!(version("7.0.beta") < version("7.0"));
+        assert!(version("7.0.beta") < version("7"));
+        assert!(version("7.0.beta") < version("7.0.1"));
+        assert!(version("7.0.beta") > version("6.9"));
+        assert!(version("7.0.alpha") < version("7.0.beta"));
+        assert!(version("7.0.beta.2") > version("7.0.beta"));
+        assert!(version("7.0.beta.2") < version("7.0"));
+        assert!(version("7.rc") < version("7.0.1"));
+    }
+
+    #[test]
+    fn sorts_the_version_picker() {
+        let mut versions: Vec<_> = ["7.0", "6.10", "7.0.beta", "6.9", "7.0.1"]
+            .into_iter()
+            .map(version)
+            .collect();
+        versions.sort();
+        let sorted: Vec<String> = versions.iter().map(ToString::to_string).collect();
+        assert_eq!(sorted, ["6.9", "6.10", "7.0.beta", "7.0", "7.0.1"]);
+    }
+}
@@ -26,4 +26,97 @@ export enum EventNames {
   UpdateProgress = 'update:progress',
   DownloadProgress = 'download:progress',
   UnpackProgress = 'unpack:progress',
-}
\ No newline at end of file
+  GameExited = 'game:exited',
+}
+export interface RemoteData {
+  content: string;
+  // Set when the update server was unreachable and content comes from the local cache.
+  cachedAt: string | null;
+}
+
+// How the published patch version relates to the installed one.
+export type VersionComparison = 'newer' | 'older' | 'equal'
+
+export interface UpdateCheck {
+  // Null before the first update.
+  installed: string | null;
+  remote: string;
+  comparison: VersionComparison;
+  // Set when the update server was unreachable and remote is the last version seen.
+  cachedAt: string | null;
+}
+
+export interface AvailableVersion {
+  version: string;
+  fileName: string;
+  size: number | null;
+  modifiedTime: string | null;
+  installed: boolean;
+  // Older than the installed version: installing it is a downgrade.
+  older: boolean;
+}
+
+// How mods/RFAD_PATCH differs from the manifest of the installed version; `/`-separated paths.
+export interface IntegrityReport {
+  version: string;
+  missing: string[];
+  modified: string[];
+  // Not part of the patch; repair leaves them alone.
+  extra: string[];
+}
+
+// What `update` would change, worked out without downloading the patch or writing anything.
+export interface DryRun {
+  fromVersion: string | null;
+  toVersion: string | null;
+  // Null when the archive cannot be listed without downloading it.
+  files: {
+    added: string[];
+    removed: string[];
+    // Existing files that get new content, or whose content cannot be compared.
+    overwritten: string[];
+    unchanged: number;
+  } | null;
+  // Only the profile files the update would change.
+  profile: {
+    fileName: string;
+    lines: { kind: 'added' | 'removed'; line: number; text: string }[];
+  }[];
+}
+
+// An optional package published in launcher-packages.json, or one installed earlier.
+export interface PackageStatus {
+  id: string;
+  name: string;
+  description: string | null;
+  installed: string | null;
+  // Null when the package is no longer published.
+  remote: string | null;
+  comparison: VersionComparison | null;
+  size: number | null;
+}
+
+export type LauncherErrorKind = 'auth' | 'notFound' | 'quota' | 'network' | 'decode' | 'config' | 'io' | 'corrupted' | 'gameRunning' | 'cancelled'
+
+// Why the install was refused before anything was touched.
+export type PreflightProblem =
+  | { kind: 'unsafePath'; entry: string }
+  | { kind: 'notEnoughSpace'; required: number; available: number }
+  | { kind: 'notWritable'; path: string; reason: string }
+
+export type LauncherError =
+  | { kind: LauncherErrorKind; message: string }
+  | { kind: 'preflight'; message: PreflightProblem[] }
+
+// An update the launcher did not get to finish, recorded in update_journal.json.
+export interface InterruptedUpdate {
+  target:
+    | { kind: 'archive'; fileName: string; version: string | null }
+    | { kind: 'staged'; version: string }
+    | { kind: 'delta'; version: string }
+    | { kind: 'package'; id: string; version: string };
+  phase: 'downloading' | 'installing' | 'loadOrder';
+  fromVersion: string | null;
+  startedAt: string;
+  updatedAt: string;
+}
@@ -0,0 +1,41 @@
+import type { LauncherError, LauncherErrorKind, PreflightProblem } from '~/types/types';
+
+const descriptions: Record<LauncherErrorKind, string> = {
+  auth: 'Не удалось авторизоваться на сервере обновлений.',
+  notFound: 'Файл обновления не найден на сервере. Свяжитесь с разработчиками.',
+  quota: 'Сервер обновлений временно ограничил скачивание. Попробуйте позже.',
+  network: 'Нет подключения к интернету.',
+  decode: 'Сервер обновлений вернул некорректный ответ.',
+  config: 'Ошибка в launcher.toml.',
+  io: 'Не удалось записать файлы на диск.',
+  corrupted: 'Скачанный файл повреждён.',
+  gameRunning: 'Закройте игру и Mod Organizer перед установкой обновления.',
+  cancelled: 'Обновление отменено, установленный патч не изменился.',
+}
+
+const gigabytes = (bytes: number): string => (bytes / 1024 ** 3).toFixed(1)
+
+const describeProblem = (problem: PreflightProblem): string => {
+  switch (problem.kind) {
+    case 'unsafePath':
+      return `Архив содержит недопустимый путь: ${problem.entry}.`
+    case 'notEnoughSpace':
+      return `Недостаточно места на диске: нужно ${gigabytes(problem.required)} ГБ, свободно ${gigabytes(problem.available)} ГБ.`
+    case 'notWritable':
+      return `Нет прав на запись в ${problem.path}.`
+  }
+}
+
+export const isLauncherError = (e: unknown): e is LauncherError =>
+  typeof e === 'object' && e !== null && 'kind' in e && 'message' in e
+
+export const describeError = (e: unknown): string => {
+  if (isLauncherError(e)) {
+    if (e.kind === 'preflight')
+      return e.message.map(describeProblem).join(' ')
+
+    return descriptions[e.kind] ?? e.message
+  }
+
+  return String(e)
+}